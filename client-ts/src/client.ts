@@ -1,8 +1,11 @@
 import { strict as assert } from "assert";
-import { StateManagerServiceClientImpl, Checkpoint } from "./gen/proto/state_manager/state_manager";
-import { Client as GrpcClient, requestCallback, credentials } from "@grpc/grpc-js";
+import {
+  StateManagerServiceClientImpl, Checkpoint, Backend, Retention, Retention_Kind, Quota, AppInfoResponse
+} from "./gen/proto/state_manager/state_manager";
+import { Client as GrpcClient, Metadata, requestCallback, credentials } from "@grpc/grpc-js";
 import { sleep } from "@proxima-one/proxima-utils";
 
+export { Backend, Retention, Retention_Kind, Quota };
 export type CheckpointId = string;
 
 export class Client {
@@ -13,7 +16,14 @@ export class Client {
   constructor(
     readonly grpc: GrpcClient,
     readonly appId: string,
+    // Bearer token, required if the server has authorization enabled
+    token?: string,
   ) {
+    const metadata = new Metadata();
+    if (token) {
+      metadata.set("authorization", `Bearer ${token}`);
+    }
+
     const sendRequest = (path: string, data: Uint8Array): Promise<Uint8Array> => {
       return new Promise((resolve, reject) => {
         const requestCallback: requestCallback<any> = (err, res) => {
@@ -29,7 +39,7 @@ export class Client {
         }
 
         // Using passThrough as the serialize and deserialize functions
-        grpc.makeUnaryRequest(path, passThrough, passThrough, data, requestCallback);
+        grpc.makeUnaryRequest(path, passThrough, passThrough, data, metadata, requestCallback);
       });
     };
 
@@ -54,8 +64,9 @@ export class Client {
   }
 
 
-  async initApp(): Promise<void> {
-    const response = await this.rpc.InitApp({ appId: this.appId });
+  // `retention` and `quota` replace the ones of an existing app, setting `quota` needs admin access
+  async initApp(backend: Backend = Backend.DEFAULT, retention?: Retention, quota?: Quota): Promise<void> {
+    const response = await this.rpc.InitApp({ appId: this.appId, backend, retention, quota });
     this.etag = response.etag;
   }
 
@@ -65,6 +76,13 @@ export class Client {
     return Object.fromEntries(response.parts.map(part => [part.key, Uint8Array.from(part.value)]));
   }
 
+  // Values of the keys when the checkpoint was created
+  async getAt(checkpointId: CheckpointId, keys: string[]): Promise<Record<string, Uint8Array>> {
+    const response = await this.rpc.GetAt({ appId: this.appId, checkpointId, keys });
+    this.etag = response.etag;
+    return Object.fromEntries(response.parts.map(part => [part.key, Uint8Array.from(part.value)]));
+  }
+
   async set(parts: Record<string, Uint8Array>): Promise<void> {
     assert(this.etag);
     const pbParts = Object.entries(parts).map(([key, value]) => ({ key, value }));
@@ -76,6 +94,16 @@ export class Client {
     this.etag = response.etag;
   }
 
+  async delete(keys: string[]): Promise<void> {
+    assert(this.etag);
+    const response = await this.rpc.Delete({
+      appId: this.appId,
+      etag: this.etag,
+      keys,
+    });
+    this.etag = response.etag;
+  }
+
   async checkpoints(): Promise<Checkpoint[]> {
     const response = await this.rpc.Checkpoints({ appId: this.appId });
     this.etag = response.etag;
@@ -91,6 +119,19 @@ export class Client {
     return response.id;
   }
 
+  // Sets the parts and creates a checkpoint with the payload, either both or none are applied.
+  // The id is empty if the commit is pending: it's applied before the next request and must
+  // not be retried
+  async commit(parts: Record<string, Uint8Array>, payload: string): Promise<CheckpointId> {
+    assert(this.etag);
+    const pbParts = Object.entries(parts).map(([key, value]) => ({ key, value }));
+    const response = await this.rpc.Commit({
+      appId: this.appId, etag: this.etag, parts: pbParts, payload
+    });
+    this.etag = response.etag;
+    return response.checkpointId;
+  }
+
   async revert(id: CheckpointId): Promise<void> {
     assert(this.etag);
     const response = await this.rpc.Revert({
@@ -99,6 +140,46 @@ export class Client {
     this.etag = response.etag;
   }
 
+  async pinCheckpoint(id: CheckpointId): Promise<void> {
+    assert(this.etag);
+    const response = await this.rpc.PinCheckpoint({
+      appId: this.appId, etag: this.etag, checkpointId: id
+    });
+    this.etag = response.etag;
+  }
+
+  async unpinCheckpoint(id: CheckpointId): Promise<void> {
+    assert(this.etag);
+    const response = await this.rpc.UnpinCheckpoint({
+      appId: this.appId, etag: this.etag, checkpointId: id
+    });
+    this.etag = response.etag;
+  }
+
+  // Creates a new app from the checkpoint of this one, which needs write access to the new app
+  async fork(checkpointId: CheckpointId, newAppId: string): Promise<void> {
+    await this.rpc.ForkApp({ sourceAppId: this.appId, checkpointId, newAppId });
+  }
+
+  // Needs admin access to the app
+  async info(): Promise<AppInfoResponse> {
+    const response = await this.rpc.AppInfo({ appId: this.appId });
+    this.etag = response.etag;
+    return response;
+  }
+
+  // Ids of all the apps on the server the token has admin access to, not only of this one
+  async listApps(prefix: string = ""): Promise<string[]> {
+    const appIds: string[] = [];
+    let continuationToken = "";
+    do {
+      const response = await this.rpc.ListApps({ prefix, limit: 0, continuationToken });
+      appIds.push(...response.appIds);
+      continuationToken = response.continuationToken;
+    } while (continuationToken);
+    return appIds;
+  }
+
   async cleanup(untilCheckpoint: CheckpointId): Promise<void> {
     assert(this.etag);
     const response = await this.rpc.Cleanup({
@@ -117,11 +198,15 @@ export class Client {
 }
 
 export function createNoAuthClient(address: string, appId: string) {
+  return createClient(address, appId);
+}
+
+export function createClient(address: string, appId: string, token?: string) {
   const MB = 2 ** 20;
   const creds = address.endsWith("443") ? credentials.createSsl() : credentials.createInsecure();
   const grpc = new GrpcClient(address, creds, {
     "grpc.max_receive_message_length": 128 * MB,
     "grpc.max_send_message_length": 128 * MB,
   });
-  return new Client(grpc, appId);
+  return new Client(grpc, appId, token);
 }
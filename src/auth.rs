@@ -0,0 +1,240 @@
+use log::warn;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use tonic::{service::Interceptor, Request, Status};
+
+/// Access level to an app. Every scope includes the ones before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+  Read,
+  Write,
+  Admin,
+}
+
+/// Tokens accepted by the server, usually loaded from a JSON file:
+/// ```json
+/// {
+///   "tokens": [
+///     { "name": "indexer", "token": "secret", "apps": { "indexer-*": "write", "*": "read" } }
+///   ]
+/// }
+/// ```
+#[derive(Debug, Deserialize)]
+pub struct AuthConfig {
+  tokens: Vec<TokenConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenConfig {
+  // Used only for logging
+  name: String,
+  token: String,
+  // App id patterns where `*` matches any sequence of characters
+  apps: HashMap<String, Scope>,
+}
+
+impl AuthConfig {
+  pub fn load(path: impl AsRef<Path>) -> Result<Self, Box<dyn std::error::Error>> {
+    Self::parse(&std::fs::read_to_string(path)?)
+  }
+
+  pub fn parse(contents: &str) -> Result<Self, Box<dyn std::error::Error>> {
+    Ok(serde_json::from_str(contents)?)
+  }
+}
+
+/// Scopes of the caller, attached to requests by [`AuthInterceptor`].
+#[derive(Debug)]
+pub struct Grants {
+  name: String,
+  apps: Vec<(String, Scope)>,
+  // Whether the authorization is disabled and the caller is unknown
+  anonymous: bool,
+}
+
+impl Grants {
+  fn anonymous(scope: Scope) -> Self {
+    Self {
+      name: "anonymous".to_owned(),
+      apps: vec![("*".to_owned(), scope)],
+      anonymous: true,
+    }
+  }
+
+  pub fn allows(&self, app_id: &str, scope: Scope) -> bool {
+    self
+      .apps
+      .iter()
+      .filter(|(pattern, _)| matches_pattern(pattern, app_id))
+      .any(|(_, granted)| *granted >= scope)
+  }
+
+  pub fn check(&self, app_id: &str, scope: Scope) -> Result<(), Status> {
+    if self.allows(app_id, scope) {
+      Ok(())
+    } else {
+      warn!("{} was denied {:?} access to app {}", self.name, scope, app_id);
+      Err(Status::permission_denied(format!(
+        "{} has no {:?} access to app {}",
+        self.name, scope, app_id
+      )))
+    }
+  }
+}
+
+/// Checks bearer tokens from the `authorization` metadata.
+#[derive(Clone)]
+pub struct AuthInterceptor {
+  // `None` disables the authorization, every caller gets the anonymous grants
+  tokens: Option<Arc<HashMap<String, Arc<Grants>>>>,
+  anonymous: Arc<Grants>,
+}
+
+impl AuthInterceptor {
+  // Without a config every caller gets write access to all the apps, but no admin access
+  pub fn new(config: Option<AuthConfig>) -> Self {
+    let tokens = config.map(|config| {
+      let tokens = config
+        .tokens
+        .into_iter()
+        .map(|token| {
+          let grants = Grants {
+            name: token.name,
+            apps: token.apps.into_iter().collect(),
+            anonymous: false,
+          };
+          (token.token, Arc::new(grants))
+        })
+        .collect();
+      Arc::new(tokens)
+    });
+    Self {
+      tokens,
+      anonymous: Arc::new(Grants::anonymous(Scope::Write)),
+    }
+  }
+
+  // Gives admin access to all the apps to every caller if the authorization is disabled
+  pub fn with_insecure_admin(mut self) -> Self {
+    self.anonymous = Arc::new(Grants::anonymous(Scope::Admin));
+    self
+  }
+
+  fn grants(&self, request: &Request<()>) -> Result<Arc<Grants>, Status> {
+    let tokens = match &self.tokens {
+      Some(tokens) => tokens,
+      None => return Ok(self.anonymous.clone()),
+    };
+    let token = request
+      .metadata()
+      .get("authorization")
+      .and_then(|value| value.to_str().ok())
+      .and_then(|value| value.strip_prefix("Bearer "))
+      .ok_or_else(|| Status::unauthenticated("Missing bearer token"))?;
+    tokens
+      .get(token)
+      .cloned()
+      .ok_or_else(|| Status::unauthenticated("Unknown token"))
+  }
+}
+
+impl Interceptor for AuthInterceptor {
+  fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+    let grants = self.grants(&request)?;
+    request.extensions_mut().insert(grants);
+    Ok(request)
+  }
+}
+
+/// Checks that the caller of an intercepted request has `scope` access to the app.
+pub fn authorize<T>(request: &Request<T>, app_id: &str, scope: Scope) -> Result<(), Status> {
+  match request.extensions().get::<Arc<Grants>>() {
+    Some(grants) => grants.check(app_id, scope),
+    None => Err(Status::permission_denied("Request was not authenticated")),
+  }
+}
+
+/// Whether the authorization is disabled, so the caller of the request is unknown.
+pub fn is_anonymous<T>(request: &Request<T>) -> bool {
+  request
+    .extensions()
+    .get::<Arc<Grants>>()
+    .is_some_and(|grants| grants.anonymous)
+}
+
+/// Like [`authorize`], but doesn't log denials, e.g. to filter a list of apps.
+pub fn is_authorized<T>(request: &Request<T>, app_id: &str, scope: Scope) -> bool {
+  request
+    .extensions()
+    .get::<Arc<Grants>>()
+    .is_some_and(|grants| grants.allows(app_id, scope))
+}
+
+fn matches_pattern(pattern: &str, value: &str) -> bool {
+  match pattern.split_once('*') {
+    None => pattern == value,
+    Some((prefix, rest)) => {
+      let value = match value.strip_prefix(prefix) {
+        Some(value) => value,
+        None => return false,
+      };
+      // The star matches the shortest possible part, the rest is matched recursively
+      (0..=value.len())
+        .filter(|&i| value.is_char_boundary(i))
+        .any(|i| matches_pattern(rest, &value[i..]))
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_grants() {
+    let config = AuthConfig::parse(
+      r#"{"tokens": [{"name": "a", "token": "t", "apps": {"team-a-*": "write", "*": "read"}}]}"#,
+    )
+    .unwrap();
+    let mut interceptor = AuthInterceptor::new(Some(config));
+
+    let mut request = Request::new(());
+    request
+      .metadata_mut()
+      .insert("authorization", "Bearer t".parse().unwrap());
+    let request = interceptor.call(request).unwrap();
+    assert!(authorize(&request, "team-a-app", Scope::Write).is_ok());
+    assert!(authorize(&request, "team-a-app", Scope::Admin).is_err());
+    assert!(authorize(&request, "team-b-app", Scope::Read).is_ok());
+    assert!(authorize(&request, "team-b-app", Scope::Write).is_err());
+    assert!(is_authorized(&request, "team-a-app", Scope::Write));
+    assert!(!is_authorized(&request, "team-a-app", Scope::Admin));
+
+    assert!(interceptor.call(Request::new(())).is_err());
+    assert!(authorize(&Request::new(()), "team-a-app", Scope::Read).is_err());
+
+    assert!(!is_anonymous(&request));
+
+    // Disabled authorization doesn't give admin access unless it's explicitly insecure
+    let mut disabled = AuthInterceptor::new(None);
+    let request = disabled.call(Request::new(())).unwrap();
+    assert!(is_anonymous(&request));
+    assert!(authorize(&request, "team-b-app", Scope::Write).is_ok());
+    assert!(authorize(&request, "team-b-app", Scope::Admin).is_err());
+    let mut insecure = AuthInterceptor::new(None).with_insecure_admin();
+    let request = insecure.call(Request::new(())).unwrap();
+    assert!(authorize(&request, "team-b-app", Scope::Admin).is_ok());
+  }
+
+  #[test]
+  fn test_patterns() {
+    assert!(matches_pattern("*", ""));
+    assert!(matches_pattern("a*c", "abbc"));
+    assert!(matches_pattern("a*b*", "axbyb"));
+    assert!(!matches_pattern("a*c", "abcd"));
+    assert!(!matches_pattern("abc", "ab"));
+  }
+}
@@ -1,8 +1,16 @@
 use crate::types::{Error, Result};
 use async_trait::async_trait;
-use std::path::Path;
+use chrono::{DateTime, Utc};
+use std::path::{Component, Path, PathBuf};
 use walkdir::WalkDir;
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileInfo {
+    pub path: PathBuf,
+    pub size: u64,
+    pub last_modified: DateTime<Utc>,
+}
+
 #[async_trait]
 pub trait FileStorage : Sync + Send {
     async fn upload_folder(&self, path: &Path, remote_path: &Path) -> Result<()> {
@@ -13,7 +21,7 @@ pub trait FileStorage : Sync + Send {
                 continue;
             }
             let entry_path = entry.into_path();
-            let remote_entry_path = remote_path.join(entry_path.strip_prefix(&path).unwrap());
+            let remote_entry_path = remote_path.join(entry_path.strip_prefix(path).unwrap());
             // TODO: check if parallel upload is faster
             self.upload_file(entry_path.as_path(), remote_entry_path.as_path()).await?;
         }
@@ -23,4 +31,88 @@ pub trait FileStorage : Sync + Send {
     async fn upload_file(&self, path: &Path, remote_path: &Path) -> Result<()>;
 
     async fn upload_buffer(&self, bytes: &[u8], remote_path: &Path) -> Result<()>;
+
+    async fn download_folder(&self, remote_path: &Path, path: &Path) -> Result<()> {
+        for file in self.list(remote_path).await? {
+            let relative_path = file.path;
+            // A tampered listing must not write outside of `path`
+            let is_normal = |component| matches!(component, Component::Normal(_));
+            if relative_path.as_os_str().is_empty() || !relative_path.components().all(is_normal) {
+                return Err(Error::DbError(format!(
+                    "Invalid file {} in {}",
+                    relative_path.display(),
+                    remote_path.display()
+                )));
+            }
+            let entry_path = path.join(&relative_path);
+            if let Some(parent) = entry_path.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            // TODO: check if parallel download is faster
+            self.download_file(remote_path.join(&relative_path).as_path(), entry_path.as_path()).await?;
+        }
+        Ok(())
+    }
+
+    async fn download_file(&self, remote_path: &Path, path: &Path) -> Result<()>;
+
+    async fn download_buffer(&self, remote_path: &Path) -> Result<Vec<u8>>;
+
+    /// Lists all the files under `remote_path`, paths are relative to it
+    async fn list(&self, remote_path: &Path) -> Result<Vec<FileInfo>>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::random_string;
+
+    // Lists the given files, which are downloaded empty
+    struct ListedFileStorage(Vec<PathBuf>);
+
+    #[async_trait]
+    impl FileStorage for ListedFileStorage {
+        async fn upload_file(&self, _path: &Path, _remote_path: &Path) -> Result<()> {
+            Err(Error::Unimplemented("upload_file".to_owned()))
+        }
+
+        async fn upload_buffer(&self, _bytes: &[u8], _remote_path: &Path) -> Result<()> {
+            Err(Error::Unimplemented("upload_buffer".to_owned()))
+        }
+
+        async fn download_file(&self, _remote_path: &Path, path: &Path) -> Result<()> {
+            std::fs::write(path, "")?;
+            Ok(())
+        }
+
+        async fn download_buffer(&self, _remote_path: &Path) -> Result<Vec<u8>> {
+            Err(Error::Unimplemented("download_buffer".to_owned()))
+        }
+
+        async fn list(&self, _remote_path: &Path) -> Result<Vec<FileInfo>> {
+            let files = self.0.iter().map(|path| FileInfo {
+                path: path.clone(),
+                size: 0,
+                last_modified: Utc::now(),
+            });
+            Ok(files.collect())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_download_folder() {
+        let root = std::env::temp_dir().join(format!("download-{}", random_string(8)));
+        let path = root.join("app");
+        let storage = ListedFileStorage(vec!["a".into(), "dir/b".into()]);
+        storage.download_folder(Path::new("/snapshot"), &path).await.unwrap();
+        assert!(path.join("a").exists() && path.join("dir/b").exists());
+
+        for file in ["../outside", "dir/../../outside", "/outside", "./a", ""] {
+            let storage = ListedFileStorage(vec![file.into()]);
+            let result = storage.download_folder(Path::new("/snapshot"), &path).await;
+            assert!(matches!(result, Err(Error::DbError(_))), "{}", file);
+        }
+        assert!(!root.join("outside").exists());
+        std::fs::remove_dir_all(root).unwrap();
+    }
 }
@@ -1,16 +1,38 @@
-use super::interface::FileStorage;
+use super::interface::{FileInfo, FileStorage};
 use crate::types::Result;
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 use s3::Bucket;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 pub struct S3FileStorage {
     bucket: Bucket,
+    // Prepended to all the keys, without leading and trailing slashes
+    prefix: String,
 }
 
 impl S3FileStorage {
     pub fn new(s3_bucket: Bucket) -> Self {
-        Self { bucket: s3_bucket }
+        Self {
+            bucket: s3_bucket,
+            prefix: String::new(),
+        }
+    }
+
+    pub fn with_prefix(mut self, prefix: &str) -> Self {
+        self.prefix = prefix.trim_matches('/').to_owned();
+        self
+    }
+
+    // Object keys are stored without the leading slash
+    fn key(&self, remote_path: &Path) -> String {
+        let path = remote_path.to_string_lossy();
+        let path = path.trim_matches('/');
+        if self.prefix.is_empty() {
+            path.to_owned()
+        } else {
+            format!("{}/{}", self.prefix, path)
+        }
     }
 }
 
@@ -19,15 +41,50 @@ impl FileStorage for S3FileStorage {
     async fn upload_file(&self, path: &Path, remote_path: &Path) -> Result<()> {
         let mut file = tokio::fs::File::open(path).await?;
         self.bucket
-            .put_object_stream(&mut file, remote_path.to_string_lossy())
+            .put_object_stream(&mut file, self.key(remote_path))
             .await?;
         Ok(())
     }
 
     async fn upload_buffer(&self, bytes: &[u8], remote_path: &Path) -> Result<()> {
         self.bucket
-            .put_object(remote_path.to_string_lossy(), bytes)
+            .put_object(self.key(remote_path), bytes)
+            .await?;
+        Ok(())
+    }
+
+    async fn download_file(&self, remote_path: &Path, path: &Path) -> Result<()> {
+        let mut file = tokio::fs::File::create(path).await?;
+        self.bucket
+            .get_object_stream(self.key(remote_path), &mut file)
             .await?;
         Ok(())
     }
+
+    async fn download_buffer(&self, remote_path: &Path) -> Result<Vec<u8>> {
+        let response = self
+            .bucket
+            .get_object(self.key(remote_path))
+            .await?;
+        Ok(response.into())
+    }
+
+    async fn list(&self, remote_path: &Path) -> Result<Vec<FileInfo>> {
+        let prefix = format!("{}/", self.key(remote_path));
+        let mut result = Vec::new();
+        for page in self.bucket.list(prefix.clone(), None).await? {
+            for object in page.contents {
+                if let Some(relative_path) = object.key.strip_prefix(&prefix) {
+                    let last_modified = DateTime::parse_from_rfc3339(&object.last_modified)
+                        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+                    result.push(FileInfo {
+                        path: PathBuf::from(relative_path),
+                        size: object.size,
+                        last_modified: last_modified.with_timezone(&Utc),
+                    });
+                }
+            }
+        }
+        Ok(result)
+    }
 }
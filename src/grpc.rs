@@ -1,45 +1,66 @@
+use crate::auth::{self, Scope};
 use crate::file_storage::{interface::FileStorage};
 use crate::proto::{self, state_manager_service_server::StateManagerService};
 use crate::service::interface::{self, AppStateManager, StateManager};
-use crate::types::{Error, KeyValue};
+use crate::service::quota::{NewKeys, Quota};
+use crate::service::retention::Retention;
+use crate::service::watch::{ChangeEvent, ChangeReceiver};
+use crate::storage::any::Backend;
+use crate::types::{validate_key, AppId, Error, KeyRange, KeyValue};
+use crate::utils::spool::Spool;
+use tokio_stream::StreamExt;
 use log::{error, info};
-use rand::{distributions::Alphanumeric, Rng};
+use std::collections::{BTreeSet, HashSet};
 use std::fmt::Display;
-use tonic::{Request, Response, Status};
+use std::path::PathBuf;
+use std::pin::Pin;
+use tokio_stream::Stream;
+use tonic::{Request, Response, Status, Streaming};
 
+const DEFAULT_SCAN_LIMIT: u32 = 1000;
+const MAX_SCAN_LIMIT: u32 = 100_000;
+const STREAM_BATCH_SIZE: usize = 100;
+// Every listed snapshot is read from the snapshot storage, so the pages are smaller
+const DEFAULT_SNAPSHOTS_LIMIT: u32 = 100;
+const MAX_SNAPSHOTS_LIMIT: u32 = 1000;
+// Lets clients without a token remove apps if the authorization is disabled, as before it existed
 const ADMIN_TOKEN: &str = "iknowwhatimdoing";
+// Number of Watch responses buffered for a client
+const WATCH_BUFFER: usize = 16;
+
+type ResponseStream<T> = Pin<Box<dyn Stream<Item = Result<T, Status>> + Send>>;
 
 #[derive(Debug)]
 pub struct GrpcService<StateManager, FileStorage> {
   manager: StateManager,
-  // Some string which is different across process restarts
-  run_id: String,
   snapshot_storage: Option<FileStorage>,
+  // Directory for BulkSet parts being received
+  spool_dir: PathBuf,
 }
 
 impl<TStateManager: StateManager, TFileStorage: FileStorage>
   GrpcService<TStateManager, TFileStorage>
 {
   pub fn new(manager: TStateManager) -> Self {
-    let run_id = rand::thread_rng()
-      .sample_iter(&Alphanumeric)
-      .take(6)
-      .map(char::from)
-      .collect();
     GrpcService {
       manager,
-      run_id,
       snapshot_storage: None,
+      spool_dir: std::env::temp_dir(),
     }
   }
 
+  pub fn with_spool_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+    self.spool_dir = dir.into();
+    self
+  }
+
   pub fn with_snapshot_storage(mut self, storage: TFileStorage) -> Self {
     self.snapshot_storage = Some(storage);
     self
   }
 
   pub fn get_etag(&self, app: &TStateManager::AppStateManager) -> String {
-    format!("{}-{}", self.run_id, app.modifications_number())
+    app.etag()
   }
 
   pub fn check_etag(&self, etag: &str, app: &TStateManager::AppStateManager) -> Result<(), Status> {
@@ -60,7 +81,7 @@ impl<TStateManager: StateManager, TFileStorage: FileStorage>
     f: impl FnOnce(&mut TStateManager::AppStateManager) -> Result<Out, Status>,
   ) -> Result<Response<Resp>, Status> {
     let start = std::time::Instant::now();
-    let result = self.manager.with_app(id, |app| {
+    let result = self.manager.with_app(&AppId::new(id)?, |app| {
       let result = f(app)?;
       Ok(Response::new(Resp::with_etag(result, self.get_etag(app))))
     })?;
@@ -68,15 +89,278 @@ impl<TStateManager: StateManager, TFileStorage: FileStorage>
     result
   }
 
-  fn remove_app(
+  // Same as `with_app` for Revert and Reset, see `StateManager::with_app_replacing_head`
+  pub fn with_app_replacing_head<Out, Resp: WithEtag<Out>>(
     &self,
     id: &str,
-    admin_token: &str,
-  ) -> Result<Response<proto::RemoveAppResponse>, Status> {
-    if admin_token != ADMIN_TOKEN {
-      return Err(tonic::Status::permission_denied("Unauthorized"));
+    f: impl FnOnce(&mut TStateManager::AppStateManager) -> Result<Out, Status>,
+  ) -> Result<Response<Resp>, Status> {
+    let start = std::time::Instant::now();
+    let result = self.manager.with_app_replacing_head(&AppId::new(id)?, |app| {
+      let result = f(app)?;
+      Ok(Response::new(Resp::with_etag(result, self.get_etag(app))))
+    })?;
+    info!("App request handled in {:?}", start.elapsed());
+    result
+  }
+
+  fn scan(
+    &self,
+    request: &proto::ScanRequest,
+  ) -> Result<Response<ResponseStream<proto::ScanResponse>>, Status> {
+    let limit = match request.limit {
+      0 => DEFAULT_SCAN_LIMIT,
+      limit => limit.min(MAX_SCAN_LIMIT),
+    } as usize;
+    let mut range = KeyRange {
+      prefix: request.prefix.clone(),
+      start: request.start.clone(),
+      end: Some(request.end.clone()).filter(|end| !end.is_empty()),
+    };
+    if !request.continuation_token.is_empty() {
+      // The smallest key greater than the last returned one
+      range.start = range.start.max(format!("{}\0", request.continuation_token));
+    }
+
+    let app_id = AppId::new(&request.app_id)?;
+    let (mut parts, etag) = self.manager.with_app(&app_id, |app| {
+      // Fetch one extra key to find out whether the range has more keys
+      app
+        .scan(&range, limit + 1)
+        .map(|parts| (parts, self.get_etag(app)))
+    })??;
+    let continuation_token = if parts.len() > limit {
+      parts.truncate(limit);
+      parts.last().unwrap().key.clone()
+    } else {
+      String::new()
+    };
+
+    let responses = batches(parts.into_iter().map(From::from), |parts, is_last| {
+      proto::ScanResponse {
+        etag: etag.clone(),
+        parts,
+        continuation_token: if is_last { continuation_token.clone() } else { String::new() },
+      }
+    });
+    Ok(Response::new(Box::pin(tokio_stream::iter(
+      responses.into_iter().map(Ok),
+    ))))
+  }
+
+  fn diff(
+    &self,
+    request: &proto::DiffRequest,
+  ) -> Result<Response<ResponseStream<proto::DiffResponse>>, Status> {
+    let limit = match request.limit {
+      0 => DEFAULT_SCAN_LIMIT,
+      limit => limit.min(MAX_SCAN_LIMIT),
+    } as usize;
+    let mut range = KeyRange {
+      prefix: request.prefix.clone(),
+      start: request.start.clone(),
+      end: Some(request.end.clone()).filter(|end| !end.is_empty()),
+    };
+    if !request.continuation_token.is_empty() {
+      range.start = range.start.max(format!("{}\0", request.continuation_token));
+    }
+    let from = Some(request.from_checkpoint.as_str()).filter(|id| !id.is_empty());
+    let to = Some(request.to_checkpoint.as_str()).filter(|id| !id.is_empty());
+    if from.is_none() && to.is_none() {
+      return Err(Status::invalid_argument("At least one checkpoint is required"));
+    }
+
+    let app_id = AppId::new(&request.app_id)?;
+    let (mut diffs, etag) = self.manager.with_app(&app_id, |app| {
+      app
+        .diff(from, to, &range, limit + 1)
+        .map(|diffs| (diffs, self.get_etag(app)))
+    })??;
+    let continuation_token = if diffs.len() > limit {
+      diffs.truncate(limit);
+      diffs.last().unwrap().key.clone()
+    } else {
+      String::new()
+    };
+
+    let include_values = request.include_values;
+    let changes = diffs.into_iter().map(|diff| {
+      let kind = match (&diff.old, &diff.new) {
+        (None, _) => proto::key_change::Kind::Added,
+        (_, None) => proto::key_change::Kind::Removed,
+        _ => proto::key_change::Kind::Modified,
+      };
+      let (old_value, new_value) = if include_values {
+        (diff.old.unwrap_or_default(), diff.new.unwrap_or_default())
+      } else {
+        Default::default()
+      };
+      proto::KeyChange {
+        key: diff.key,
+        kind: kind.into(),
+        old_value,
+        new_value,
+      }
+    });
+    let responses = batches(changes, |changes, is_last| proto::DiffResponse {
+      etag: etag.clone(),
+      changes,
+      continuation_token: if is_last { continuation_token.clone() } else { String::new() },
+    });
+    Ok(Response::new(Box::pin(tokio_stream::iter(
+      responses.into_iter().map(Ok),
+    ))))
+  }
+
+  // `header` is the first message of the stream, its parts are taken out and passed in `parts`
+  async fn bulk_set(
+    &self,
+    header: &proto::BulkSetRequest,
+    mut parts: Vec<proto::Part>,
+    mut stream: impl Stream<Item = Result<proto::BulkSetRequest, Status>> + Unpin,
+  ) -> Result<Response<proto::BulkSetResponse>, Status> {
+    let app_id = AppId::new(&header.app_id)?;
+    // Fails before the whole stream is received if the etag is already stale
+    let quota = self.manager.with_app(&app_id, |app| {
+      self.check_etag(&header.etag, app)?;
+      Ok::<_, Status>(app.quota())
+    })??;
+
+    let mut spool = Spool::create(&self.spool_dir)?;
+    // Tracked only with a quota of keys, which bounds their number
+    let mut new_keys = quota.max_keys.map(|_| NewKeys::default());
+    loop {
+      let chunk: Vec<_> = parts
+        .into_iter()
+        .map(|part| KeyValue {
+          key: part.key,
+          value: part.value,
+        })
+        .collect();
+      chunk.iter().try_for_each(|part| validate_key(&part.key))?;
+      quota.check_values(&chunk)?;
+      if let Some(new_keys) = &mut new_keys {
+        let keys: Vec<_> = chunk.iter().map(|part| part.key.as_str()).collect();
+        let existing: HashSet<_> = self
+          .manager
+          .with_app(&app_id, |app| app.get(&keys))??
+          .into_iter()
+          .map(|part| part.key)
+          .collect();
+        for part in chunk.iter().filter(|part| !existing.contains(&part.key)) {
+          new_keys.add(&part.key, part.value.len() as u64);
+        }
+        quota.check_bulk(new_keys)?;
+      }
+      for part in chunk {
+        spool.push(&part.key, &part.value)?;
+      }
+      match stream.next().await.transpose()? {
+        Some(message) => parts = message.parts,
+        None => break,
+      }
     }
-    self.manager.drop_app(id)?;
+    let count = spool.count();
+    info!("[{}]: Writing {} parts", app_id, count);
+
+    self.with_app(&header.app_id, |app| {
+      self.check_etag(&header.etag, app)?;
+      let payload = Some(header.checkpoint_payload.as_str()).filter(|_| header.create_checkpoint);
+      Ok((count, app.bulk_set(spool, payload)?))
+    })
+  }
+
+  async fn snapshot_info(
+    &self,
+    storage: &TFileStorage,
+    app_id: &AppId,
+    snapshot_id: &str,
+  ) -> Result<proto::SnapshotInfo, Status> {
+    let info = self
+      .manager
+      .snapshot_info(storage, &snapshot_prefix(app_id, snapshot_id)?)
+      .await?;
+    Ok(proto::SnapshotInfo {
+      id: snapshot_id.to_owned(),
+      checkpoint: Some(info.checkpoint.into()),
+      size: info.size,
+      uploaded_at: info.uploaded_at.to_rfc3339(),
+    })
+  }
+
+  async fn list_snapshots(
+    &self,
+    storage: &TFileStorage,
+    request: &proto::ListSnapshotsRequest,
+  ) -> Result<Response<proto::ListSnapshotsResponse>, Status> {
+    let limit = match request.limit {
+      0 => DEFAULT_SNAPSHOTS_LIMIT,
+      limit => limit.min(MAX_SNAPSHOTS_LIMIT),
+    } as usize;
+    let app_id = AppId::new(&request.app_id)?;
+    let files = storage.list(&snapshots_prefix(&app_id)).await?;
+    // Only the manifests right under the snapshot directories, checkpoints may contain
+    // files with the same name
+    let ids: BTreeSet<_> = files
+      .iter()
+      .filter(|file| file.path.file_name() == Some("manifest.json".as_ref()))
+      .filter_map(|file| file.path.parent())
+      .filter(|parent| parent.components().count() == 1)
+      .filter_map(|parent| parent.to_str())
+      .filter(|id| *id > request.continuation_token.as_str())
+      .collect();
+    let mut ids: Vec<_> = ids.into_iter().take(limit + 1).collect();
+    let continuation_token = if ids.len() > limit {
+      ids.truncate(limit);
+      ids.last().unwrap().to_string()
+    } else {
+      String::new()
+    };
+    let mut snapshots = Vec::new();
+    for id in ids {
+      snapshots.push(self.snapshot_info(storage, &app_id, id).await?);
+    }
+    Ok(Response::new(proto::ListSnapshotsResponse {
+      snapshots,
+      continuation_token,
+    }))
+  }
+
+  // Apps the caller has no admin access to are skipped, so the page may be shorter than the limit
+  // only if it's the last one
+  fn list_apps(
+    &self,
+    request: &Request<proto::ListAppsRequest>,
+  ) -> Result<Response<proto::ListAppsResponse>, Status> {
+    let limit = match request.get_ref().limit {
+      0 => DEFAULT_SCAN_LIMIT,
+      limit => limit.min(MAX_SCAN_LIMIT),
+    } as usize;
+    let prefix = &request.get_ref().prefix;
+    let continuation_token = &request.get_ref().continuation_token;
+    let mut app_ids: Vec<_> = self
+      .manager
+      .list_apps()?
+      .into_iter()
+      .map(|id| id.to_string())
+      .filter(|id| id.starts_with(prefix.as_str()) && id > continuation_token)
+      .filter(|id| auth::is_authorized(request, id, Scope::Admin))
+      .take(limit + 1)
+      .collect();
+    let continuation_token = if app_ids.len() > limit {
+      app_ids.truncate(limit);
+      app_ids.last().unwrap().clone()
+    } else {
+      String::new()
+    };
+    Ok(Response::new(proto::ListAppsResponse {
+      app_ids,
+      continuation_token,
+    }))
+  }
+
+  fn remove_app(&self, id: &str) -> Result<Response<proto::RemoveAppResponse>, Status> {
+    self.manager.drop_app(&AppId::new(id)?)?;
     Ok(Response::new(proto::RemoveAppResponse {}))
   }
 }
@@ -89,21 +373,32 @@ impl<TStateManager: StateManager + 'static, TFileStorage: FileStorage + 'static>
     &self,
     request: Request<proto::InitAppRequest>,
   ) -> Result<Response<proto::InitAppResponse>, Status> {
+    // Otherwise the writers of an app could lift the limits protecting the other apps
+    let scope = match request.get_ref().quota {
+      Some(_) => Scope::Admin,
+      None => Scope::Write,
+    };
+    auth::authorize(&request, &request.get_ref().app_id, scope)?;
     let request = request.into_inner();
-    let result = self
-      .manager
-      .init_app(&request.app_id)
-      .map_err(From::from)
-      .and_then(|()| {
-        self
-          .manager
-          .with_app(&request.app_id, |app| {
-            Ok(Response::new(proto::InitAppResponse {
-              etag: self.get_etag(app),
-            }))
+    let options = interface::AppOptions {
+      backend: match request.backend() {
+        proto::Backend::Default => None,
+        proto::Backend::Filesystem => Some(Backend::Filesystem),
+        proto::Backend::Rocksdb => Some(Backend::Rocksdb),
+      },
+      retention: request.retention.as_ref().map(parse_retention).transpose()?.flatten(),
+      quota: request.quota.as_ref().map(parse_quota),
+    };
+    let result = AppId::new(&request.app_id)
+      .and_then(|app_id| {
+        self.manager.init_app(&app_id, &options)?;
+        self.manager.with_app(&app_id, |app| {
+          Response::new(proto::InitAppResponse {
+            etag: self.get_etag(app),
           })
-          .unwrap()
-      });
+        })
+      })
+      .map_err(From::from);
     log(&request, &result);
     result
   }
@@ -112,6 +407,7 @@ impl<TStateManager: StateManager + 'static, TFileStorage: FileStorage + 'static>
     &self,
     request: Request<proto::GetRequest>,
   ) -> Result<Response<proto::GetResponse>, Status> {
+    auth::authorize(&request, &request.get_ref().app_id, Scope::Read)?;
     let request = request.into_inner();
     let result = self.with_app(&request.app_id, |app| {
       app.get(&request.keys).map_err(From::from)
@@ -120,10 +416,83 @@ impl<TStateManager: StateManager + 'static, TFileStorage: FileStorage + 'static>
     result
   }
 
+  async fn get_at(
+    &self,
+    request: Request<proto::GetAtRequest>,
+  ) -> Result<Response<proto::GetAtResponse>, Status> {
+    auth::authorize(&request, &request.get_ref().app_id, Scope::Read)?;
+    let request = request.into_inner();
+    let result = self.with_app(&request.app_id, |app| {
+      app
+        .get_at(&request.checkpoint_id, &request.keys)
+        .map_err(From::from)
+    });
+    log(&request, &result);
+    result
+  }
+
+  type ScanStream = ResponseStream<proto::ScanResponse>;
+
+  async fn scan(
+    &self,
+    request: Request<proto::ScanRequest>,
+  ) -> Result<Response<Self::ScanStream>, Status> {
+    auth::authorize(&request, &request.get_ref().app_id, Scope::Read)?;
+    let request = request.into_inner();
+    let result = self.scan(&request);
+    log(&request, &result);
+    result
+  }
+
+  type DiffStream = ResponseStream<proto::DiffResponse>;
+
+  async fn diff(
+    &self,
+    request: Request<proto::DiffRequest>,
+  ) -> Result<Response<Self::DiffStream>, Status> {
+    auth::authorize(&request, &request.get_ref().app_id, Scope::Read)?;
+    let request = request.into_inner();
+    let result = self.diff(&request);
+    log(&request, &result);
+    result
+  }
+
+  type WatchStream = ResponseStream<proto::WatchResponse>;
+
+  async fn watch(
+    &self,
+    request: Request<proto::WatchRequest>,
+  ) -> Result<Response<Self::WatchStream>, Status> {
+    auth::authorize(&request, &request.get_ref().app_id, Scope::Read)?;
+    let request = request.into_inner();
+    let result = AppId::new(&request.app_id)
+      .map_err(Status::from)
+      .and_then(|app_id| {
+        // Subscribed under the lock, so no change after the etag is missed
+        let (receiver, etag) = self
+          .manager
+          .with_app(&app_id, |app| (app.watch(), self.get_etag(app)))?;
+        let (sender, responses) = tokio::sync::mpsc::channel(WATCH_BUFFER);
+        let first = proto::WatchResponse {
+          etag,
+          ..Default::default()
+        };
+        // The buffer is empty, so the first message always fits
+        let _ = sender.try_send(Ok(first));
+        tokio::spawn(forward_changes(request.clone(), receiver, sender));
+        let stream: Self::WatchStream =
+          Box::pin(tokio_stream::wrappers::ReceiverStream::new(responses));
+        Ok(Response::new(stream))
+      });
+    log(&request, &result);
+    result
+  }
+
   async fn set(
     &self,
     request: Request<proto::SetRequest>,
   ) -> Result<Response<proto::SetResponse>, Status> {
+    auth::authorize(&request, &request.get_ref().app_id, Scope::Write)?;
     let request = request.into_inner();
     let result = self.with_app(&request.app_id, |app| {
       self.check_etag(&request.etag, app)?;
@@ -141,10 +510,41 @@ impl<TStateManager: StateManager + 'static, TFileStorage: FileStorage + 'static>
     result
   }
 
+  async fn bulk_set(
+    &self,
+    mut request: Request<Streaming<proto::BulkSetRequest>>,
+  ) -> Result<Response<proto::BulkSetResponse>, Status> {
+    let mut header = request
+      .get_mut()
+      .message()
+      .await?
+      .ok_or_else(|| Status::invalid_argument("BulkSet stream is empty"))?;
+    auth::authorize(&request, &header.app_id, Scope::Write)?;
+    let parts = std::mem::take(&mut header.parts);
+    let result = self.bulk_set(&header, parts, request.into_inner()).await;
+    log(&header, &result);
+    result
+  }
+
+  async fn delete(
+    &self,
+    request: Request<proto::DeleteRequest>,
+  ) -> Result<Response<proto::DeleteResponse>, Status> {
+    auth::authorize(&request, &request.get_ref().app_id, Scope::Write)?;
+    let request = request.into_inner();
+    let result = self.with_app(&request.app_id, |app| {
+      self.check_etag(&request.etag, app)?;
+      app.delete(&request.keys).map_err(From::from)
+    });
+    log(&request, &result);
+    result
+  }
+
   async fn checkpoints(
     &self,
     request: Request<proto::CheckpointsRequest>,
   ) -> Result<Response<proto::CheckpointsResponse>, Status> {
+    auth::authorize(&request, &request.get_ref().app_id, Scope::Read)?;
     let request = request.into_inner();
     let result = self.with_app(&request.app_id, |app| {
       app.get_checkpoints().map_err(From::from)
@@ -157,6 +557,7 @@ impl<TStateManager: StateManager + 'static, TFileStorage: FileStorage + 'static>
     &self,
     request: Request<proto::CreateCheckpointRequest>,
   ) -> Result<Response<proto::CreateCheckpointResponse>, Status> {
+    auth::authorize(&request, &request.get_ref().app_id, Scope::Write)?;
     let request = request.into_inner();
     let result = self.with_app(&request.app_id, |app| {
       self.check_etag(&request.etag, app)?;
@@ -166,12 +567,31 @@ impl<TStateManager: StateManager + 'static, TFileStorage: FileStorage + 'static>
     result
   }
 
+  async fn commit(
+    &self,
+    request: Request<proto::CommitRequest>,
+  ) -> Result<Response<proto::CommitResponse>, Status> {
+    auth::authorize(&request, &request.get_ref().app_id, Scope::Write)?;
+    let request = request.into_inner();
+    let result = self.with_app(&request.app_id, |app| {
+      self.check_etag(&request.etag, app)?;
+      let parts = request.parts.iter().map(|part| KeyValue {
+        key: part.key.clone(),
+        value: part.value.clone(),
+      });
+      app.commit(parts.collect(), &request.payload).map_err(From::from)
+    });
+    log(&request, &result);
+    result
+  }
+
   async fn revert(
     &self,
     request: Request<proto::RevertRequest>,
   ) -> Result<Response<proto::RevertResponse>, Status> {
+    auth::authorize(&request, &request.get_ref().app_id, Scope::Write)?;
     let request = request.into_inner();
-    let result = self.with_app(&request.app_id, |app| {
+    let result = self.with_app_replacing_head(&request.app_id, |app| {
       self.check_etag(&request.etag, app)?;
       app.revert(&request.checkpoint_id).map_err(From::from)
     });
@@ -183,6 +603,7 @@ impl<TStateManager: StateManager + 'static, TFileStorage: FileStorage + 'static>
     &self,
     request: Request<proto::CleanupRequest>,
   ) -> Result<Response<proto::CleanupResponse>, Status> {
+    auth::authorize(&request, &request.get_ref().app_id, Scope::Write)?;
     let request = request.into_inner();
     let result = self.with_app(&request.app_id, |app| {
       self.check_etag(&request.etag, app)?;
@@ -192,12 +613,41 @@ impl<TStateManager: StateManager + 'static, TFileStorage: FileStorage + 'static>
     result
   }
 
+  async fn pin_checkpoint(
+    &self,
+    request: Request<proto::PinCheckpointRequest>,
+  ) -> Result<Response<proto::PinCheckpointResponse>, Status> {
+    auth::authorize(&request, &request.get_ref().app_id, Scope::Write)?;
+    let request = request.into_inner();
+    let result = self.with_app(&request.app_id, |app| {
+      self.check_etag(&request.etag, app)?;
+      app.set_pinned(&request.checkpoint_id, true).map_err(From::from)
+    });
+    log(&request, &result);
+    result
+  }
+
+  async fn unpin_checkpoint(
+    &self,
+    request: Request<proto::UnpinCheckpointRequest>,
+  ) -> Result<Response<proto::UnpinCheckpointResponse>, Status> {
+    auth::authorize(&request, &request.get_ref().app_id, Scope::Write)?;
+    let request = request.into_inner();
+    let result = self.with_app(&request.app_id, |app| {
+      self.check_etag(&request.etag, app)?;
+      app.set_pinned(&request.checkpoint_id, false).map_err(From::from)
+    });
+    log(&request, &result);
+    result
+  }
+
   async fn reset(
     &self,
     request: Request<proto::ResetRequest>,
   ) -> Result<Response<proto::ResetResponse>, Status> {
+    auth::authorize(&request, &request.get_ref().app_id, Scope::Write)?;
     let request = request.into_inner();
-    let result = self.with_app(&request.app_id, |app| {
+    let result = self.with_app_replacing_head(&request.app_id, |app| {
       self.check_etag(&request.etag, app)?;
       app.reset().map_err(From::from)
     });
@@ -209,8 +659,33 @@ impl<TStateManager: StateManager + 'static, TFileStorage: FileStorage + 'static>
     &self,
     request: Request<proto::RemoveAppRequest>,
   ) -> Result<Response<proto::RemoveAppResponse>, Status> {
+    #[allow(deprecated)]
+    let legacy_admin = auth::is_anonymous(&request) && request.get_ref().admin_token == ADMIN_TOKEN;
+    if !legacy_admin {
+      auth::authorize(&request, &request.get_ref().app_id, Scope::Admin)?;
+    }
+    let request = request.into_inner();
+    let result = self.remove_app(&request.app_id);
+    log(&request, &result);
+    result
+  }
+
+  async fn list_apps(
+    &self,
+    request: Request<proto::ListAppsRequest>,
+  ) -> Result<Response<proto::ListAppsResponse>, Status> {
+    let result = self.list_apps(&request);
+    log(request.get_ref(), &result);
+    result
+  }
+
+  async fn app_info(
+    &self,
+    request: Request<proto::AppInfoRequest>,
+  ) -> Result<Response<proto::AppInfoResponse>, Status> {
+    auth::authorize(&request, &request.get_ref().app_id, Scope::Admin)?;
     let request = request.into_inner();
-    let result = self.remove_app(&request.app_id, &request.admin_token);
+    let result = self.with_app(&request.app_id, |app| app.info().map_err(From::from));
     log(&request, &result);
     result
   }
@@ -219,16 +694,16 @@ impl<TStateManager: StateManager + 'static, TFileStorage: FileStorage + 'static>
     &self,
     request: Request<proto::UploadSnapshotRequest>,
   ) -> Result<Response<proto::UploadSnapshotResponse>, Status> {
+    auth::authorize(&request, &request.get_ref().app_id, Scope::Write)?;
     let request = request.into_inner();
 
     let result = if let Some(storage) = &self.snapshot_storage {
       let snapshot_id = chrono::Utc::now().format("%FT%H:%M:00").to_string();
-      let prefix = std::path::Path::new("/snapshots")
-        .join(&request.app_id)
-        .join(&snapshot_id);
+      let app_id = AppId::new(&request.app_id)?;
+      let prefix = snapshot_prefix(&app_id, &snapshot_id)?;
       self
         .manager
-        .store_snapshot(&request.app_id, storage, &prefix)
+        .store_snapshot(&app_id, storage, &prefix)
         .await?;
       info!("Successfully uploaded snapshot '{}'", prefix.display());
       Ok(Response::new(proto::UploadSnapshotResponse { snapshot_id }))
@@ -238,6 +713,211 @@ impl<TStateManager: StateManager + 'static, TFileStorage: FileStorage + 'static>
     log(&request, &result);
     result
   }
+
+  async fn restore_snapshot(
+    &self,
+    request: Request<proto::RestoreSnapshotRequest>,
+  ) -> Result<Response<proto::RestoreSnapshotResponse>, Status> {
+    auth::authorize(&request, &request.get_ref().app_id, Scope::Admin)?;
+    let request = request.into_inner();
+
+    let result = if let Some(storage) = &self.snapshot_storage {
+      // Failures are logged like the other results, so nothing returns early
+      async {
+        let app_id = AppId::new(&request.app_id)?;
+        let prefix = snapshot_prefix(&app_id, &request.snapshot_id)?;
+        self
+          .manager
+          .restore_snapshot(&app_id, storage, &prefix)
+          .await?;
+        info!("Successfully restored snapshot '{}'", prefix.display());
+        self.with_app(&request.app_id, |_app| Ok(()))
+      }
+      .await
+    } else {
+      Err(Status::not_found("Snapshot storage was not initialized"))
+    };
+    log(&request, &result);
+    result
+  }
+
+  async fn fork_app(
+    &self,
+    request: Request<proto::ForkAppRequest>,
+  ) -> Result<Response<proto::ForkAppResponse>, Status> {
+    auth::authorize(&request, &request.get_ref().source_app_id, Scope::Read)?;
+    auth::authorize(&request, &request.get_ref().new_app_id, Scope::Write)?;
+    let request = request.into_inner();
+    let result = AppId::new(&request.source_app_id)
+      .and_then(|source_id| {
+        let id = AppId::new(&request.new_app_id)?;
+        self.manager.fork_app(&source_id, &request.checkpoint_id, &id)
+      })
+      .map_err(Status::from)
+      .and_then(|()| self.with_app(&request.new_app_id, |_app| Ok(())));
+    log(&request, &result);
+    result
+  }
+
+  async fn list_snapshots(
+    &self,
+    request: Request<proto::ListSnapshotsRequest>,
+  ) -> Result<Response<proto::ListSnapshotsResponse>, Status> {
+    auth::authorize(&request, &request.get_ref().app_id, Scope::Read)?;
+    let request = request.into_inner();
+
+    let result = if let Some(storage) = &self.snapshot_storage {
+      self.list_snapshots(storage, &request).await
+    } else {
+      Err(Status::not_found("Snapshot storage was not initialized"))
+    };
+    log(&request, &result);
+    result
+  }
+
+  async fn get_snapshot_info(
+    &self,
+    request: Request<proto::GetSnapshotInfoRequest>,
+  ) -> Result<Response<proto::GetSnapshotInfoResponse>, Status> {
+    auth::authorize(&request, &request.get_ref().app_id, Scope::Read)?;
+    let request = request.into_inner();
+
+    let result = if let Some(storage) = &self.snapshot_storage {
+      let app_id = AppId::new(&request.app_id)?;
+      self
+        .snapshot_info(storage, &app_id, &request.snapshot_id)
+        .await
+        .map(|snapshot| {
+          Response::new(proto::GetSnapshotInfoResponse {
+            snapshot: Some(snapshot),
+          })
+        })
+    } else {
+      Err(Status::not_found("Snapshot storage was not initialized"))
+    };
+    log(&request, &result);
+    result
+  }
+}
+
+// Relative to the snapshot storage root, which is configured with the storage
+fn snapshots_prefix(app_id: &AppId) -> PathBuf {
+  PathBuf::from(app_id.as_str())
+}
+
+fn snapshot_prefix(app_id: &AppId, snapshot_id: &str) -> Result<PathBuf, Status> {
+  // Snapshot ids are generated by the server, but the client could pass anything
+  if snapshot_id.is_empty() || snapshot_id.starts_with('.') || snapshot_id.contains('/') {
+    return Err(Status::invalid_argument(format!(
+      "Invalid snapshot id {:?}",
+      snapshot_id
+    )));
+  }
+  Ok(snapshots_prefix(app_id).join(snapshot_id))
+}
+
+fn parse_retention(retention: &proto::Retention) -> Result<Option<Retention>, Status> {
+  Ok(match retention.kind() {
+    proto::retention::Kind::Default => None,
+    proto::retention::Kind::Exponential => Some(Retention::Exponential),
+    proto::retention::Kind::KeepLast if retention.count == 0 => {
+      return Err(Status::invalid_argument("KEEP_LAST retention requires a positive count"))
+    }
+    proto::retention::Kind::KeepLast => Some(Retention::KeepLast {
+      count: retention.count as usize,
+    }),
+    proto::retention::Kind::KeepFor => Some(Retention::KeepFor {
+      max_age_seconds: retention.max_age_seconds,
+    }),
+    proto::retention::Kind::KeepAll => Some(Retention::KeepAll),
+  })
+}
+
+fn parse_quota(quota: &proto::Quota) -> Quota {
+  let limit = |value: u64| Some(value).filter(|value| *value != 0);
+  Quota {
+    max_keys: limit(quota.max_keys),
+    max_bytes: limit(quota.max_bytes),
+    max_value_size: limit(quota.max_value_size),
+    max_checkpoints: limit(quota.max_checkpoints),
+  }
+}
+
+// Splits items into stream messages. There is at least one message, even if there are no items
+fn batches<T, Message>(
+  items: impl Iterator<Item = T>,
+  make_message: impl Fn(Vec<T>, bool) -> Message,
+) -> Vec<Message> {
+  let mut items = items.peekable();
+  let mut messages = Vec::new();
+  loop {
+    let batch = items.by_ref().take(STREAM_BATCH_SIZE).collect();
+    let is_last = items.peek().is_none();
+    messages.push(make_message(batch, is_last));
+    if is_last {
+      return messages;
+    }
+  }
+}
+
+// Changes of the event to the watched keys, `None` if none of them changed
+fn watch_response(request: &proto::WatchRequest, event: &ChangeEvent) -> Option<proto::WatchResponse> {
+  let mut response = proto::WatchResponse {
+    etag: event.etag.clone(),
+    resync: event.resync,
+    ..Default::default()
+  };
+  let watched = |key: &str| {
+    if request.keys.is_empty() {
+      key.starts_with(&request.prefix)
+    } else {
+      request.keys.iter().any(|watched| watched == key)
+    }
+  };
+  for change in event.changes.iter().filter(|change| watched(&change.key)) {
+    match &change.value {
+      Some(value) => response.parts.push(proto::Part {
+        key: change.key.clone(),
+        value: value.clone(),
+      }),
+      None => response.deleted_keys.push(change.key.clone()),
+    }
+  }
+  if !response.resync && response.parts.is_empty() && response.deleted_keys.is_empty() {
+    None
+  } else {
+    Some(response)
+  }
+}
+
+// Forwards the events to the client until the app is dropped or the client disconnects
+async fn forward_changes(
+  request: proto::WatchRequest,
+  mut receiver: ChangeReceiver,
+  sender: tokio::sync::mpsc::Sender<Result<proto::WatchResponse, Status>>,
+) {
+  use tokio::sync::broadcast::error::RecvError;
+  loop {
+    let event = tokio::select! {
+      event = receiver.recv() => event,
+      _ = sender.closed() => return,
+    };
+    let response = match event {
+      Ok(event) => match watch_response(&request, &event) {
+        Some(response) => Ok(response),
+        None => continue,
+      },
+      Err(RecvError::Lagged(skipped)) => Err(Status::aborted(format!(
+        "Watcher fell behind by {} changes",
+        skipped
+      ))),
+      Err(RecvError::Closed) => return,
+    };
+    let is_err = response.is_err();
+    if sender.send(response).await.is_err() || is_err {
+      return;
+    }
+  }
 }
 
 fn log<T>(request: &impl Display, result: &Result<Response<T>, Status>) {
@@ -264,6 +944,15 @@ impl From<interface::Checkpoint> for proto::Checkpoint {
     Self {
       id: checkpoint.id,
       payload: checkpoint.payload,
+      pinned: checkpoint.pinned,
+      created_at: checkpoint
+        .created_at
+        .map(|created_at| created_at.to_rfc3339())
+        .unwrap_or_default(),
+      key_count: checkpoint.key_count.unwrap_or_default(),
+      size: checkpoint.size.unwrap_or_default(),
+      modifications_number: checkpoint.modifications_number.unwrap_or_default(),
+      content_hash: checkpoint.content_hash.unwrap_or_default(),
     }
   }
 }
@@ -284,11 +973,38 @@ impl WithEtag<Vec<KeyValue>> for proto::GetResponse {
     }
   }
 }
+impl WithEtag<Vec<KeyValue>> for proto::GetAtResponse {
+  fn with_etag(from: Vec<KeyValue>, etag: impl Into<String>) -> Self {
+    Self {
+      etag: etag.into(),
+      parts: from.into_iter().map(From::from).collect(),
+    }
+  }
+}
 impl WithEtag<()> for proto::SetResponse {
   fn with_etag(_from: (), etag: impl Into<String>) -> Self {
     Self { etag: etag.into() }
   }
 }
+impl WithEtag<(u64, interface::Committed)> for proto::BulkSetResponse {
+  fn with_etag((parts_written, committed): (u64, interface::Committed), etag: impl Into<String>) -> Self {
+    let (checkpoint, pending) = match committed {
+      interface::Committed::Applied(checkpoint) => (checkpoint, false),
+      interface::Committed::Pending(_err) => (None, true),
+    };
+    Self {
+      etag: etag.into(),
+      parts_written,
+      checkpoint_id: checkpoint.map(|checkpoint| checkpoint.id).unwrap_or_default(),
+      pending,
+    }
+  }
+}
+impl WithEtag<()> for proto::DeleteResponse {
+  fn with_etag(_from: (), etag: impl Into<String>) -> Self {
+    Self { etag: etag.into() }
+  }
+}
 impl WithEtag<Vec<interface::Checkpoint>> for proto::CheckpointsResponse {
   fn with_etag(from: Vec<interface::Checkpoint>, etag: impl Into<String>) -> Self {
     Self {
@@ -297,11 +1013,26 @@ impl WithEtag<Vec<interface::Checkpoint>> for proto::CheckpointsResponse {
     }
   }
 }
-impl WithEtag<String> for proto::CreateCheckpointResponse {
-  fn with_etag(from: String, etag: impl Into<String>) -> Self {
+impl WithEtag<interface::CreatedCheckpoint> for proto::CreateCheckpointResponse {
+  fn with_etag(from: interface::CreatedCheckpoint, etag: impl Into<String>) -> Self {
     Self {
       etag: etag.into(),
-      id: from,
+      id: from.id,
+      evicted: from.evicted,
+    }
+  }
+}
+impl WithEtag<interface::Committed> for proto::CommitResponse {
+  fn with_etag(from: interface::Committed, etag: impl Into<String>) -> Self {
+    let (checkpoint, pending) = match from {
+      interface::Committed::Applied(checkpoint) => (checkpoint.unwrap_or_default(), false),
+      interface::Committed::Pending(_err) => (Default::default(), true),
+    };
+    Self {
+      etag: etag.into(),
+      checkpoint_id: checkpoint.id,
+      evicted: checkpoint.evicted,
+      pending,
     }
   }
 }
@@ -315,6 +1046,48 @@ impl WithEtag<()> for proto::CleanupResponse {
     Self { etag: etag.into() }
   }
 }
+impl WithEtag<()> for proto::PinCheckpointResponse {
+  fn with_etag(_from: (), etag: impl Into<String>) -> Self {
+    Self { etag: etag.into() }
+  }
+}
+impl WithEtag<()> for proto::UnpinCheckpointResponse {
+  fn with_etag(_from: (), etag: impl Into<String>) -> Self {
+    Self { etag: etag.into() }
+  }
+}
+impl WithEtag<()> for proto::ForkAppResponse {
+  fn with_etag(_from: (), etag: impl Into<String>) -> Self {
+    Self { etag: etag.into() }
+  }
+}
+impl WithEtag<interface::AppInfo> for proto::AppInfoResponse {
+  fn with_etag(from: interface::AppInfo, etag: impl Into<String>) -> Self {
+    Self {
+      etag: etag.into(),
+      backend: match from.backend {
+        None => proto::Backend::Default,
+        Some(Backend::Filesystem) => proto::Backend::Filesystem,
+        Some(Backend::Rocksdb) => proto::Backend::Rocksdb,
+      }
+      .into(),
+      key_count: from.key_count.unwrap_or_default(),
+      key_count_estimated: from.key_count_estimated,
+      disk_usage: from.disk_usage.unwrap_or_default(),
+      checkpoint_count: from.checkpoint_count as u32,
+      latest_checkpoint: from.latest_checkpoint.map(From::from),
+      last_write_at: from
+        .last_write_at
+        .map(|last_write_at| last_write_at.to_rfc3339())
+        .unwrap_or_default(),
+    }
+  }
+}
+impl WithEtag<()> for proto::RestoreSnapshotResponse {
+  fn with_etag(_from: (), etag: impl Into<String>) -> Self {
+    Self { etag: etag.into() }
+  }
+}
 impl WithEtag<()> for proto::ResetResponse {
   fn with_etag(_from: (), etag: impl Into<String>) -> Self {
     Self { etag: etag.into() }
@@ -325,6 +1098,11 @@ impl From<Error> for Status {
   fn from(err: Error) -> Self {
     match err {
       Error::NotFound(message) => Self::not_found(message),
+      Error::AlreadyExists(message) => Self::already_exists(message),
+      Error::Unimplemented(message) => Self::unimplemented(message),
+      Error::FailedPrecondition(message) => Self::failed_precondition(message),
+      Error::InvalidArgument(message) => Self::invalid_argument(message),
+      Error::ResourceExhausted(message) => Self::resource_exhausted(message),
       Error::DbError(message) => Self::internal(message),
       Error::IoError(err) => err.into(),
       Error::S3Error(err) => Self::unknown(format!("{}", err)),
@@ -344,6 +1122,52 @@ impl Display for proto::GetRequest {
   }
 }
 
+impl Display for proto::GetAtRequest {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(
+      f,
+      "[{}]: GetAt({:?}, {:?})",
+      self.app_id, self.checkpoint_id, self.keys
+    )
+  }
+}
+
+impl Display for proto::ScanRequest {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(
+      f,
+      "[{}]: Scan(prefix: {:?}, start: {:?}, end: {:?}, limit: {})",
+      self.app_id, self.prefix, self.start, self.end, self.limit
+    )
+  }
+}
+
+impl Display for proto::DiffRequest {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(
+      f,
+      "[{}]: Diff(from: {:?}, to: {:?}, prefix: {:?}, start: {:?}, end: {:?}, limit: {})",
+      self.app_id,
+      self.from_checkpoint,
+      self.to_checkpoint,
+      self.prefix,
+      self.start,
+      self.end,
+      self.limit
+    )
+  }
+}
+
+impl Display for proto::WatchRequest {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(
+      f,
+      "[{}]: Watch(keys: {:?}, prefix: {:?})",
+      self.app_id, self.keys, self.prefix
+    )
+  }
+}
+
 impl Display for proto::SetRequest {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
     write!(
@@ -355,6 +1179,22 @@ impl Display for proto::SetRequest {
   }
 }
 
+impl Display for proto::BulkSetRequest {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(
+      f,
+      "[{}]: BulkSet(create_checkpoint: {}, payload: {:?})",
+      self.app_id, self.create_checkpoint, self.checkpoint_payload
+    )
+  }
+}
+
+impl Display for proto::DeleteRequest {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "[{}]: Delete({:?})", self.app_id, self.keys)
+  }
+}
+
 impl Display for proto::CheckpointsRequest {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
     write!(f, "[{}]: Checkpoints()", self.app_id)
@@ -371,6 +1211,18 @@ impl Display for proto::CreateCheckpointRequest {
   }
 }
 
+impl Display for proto::CommitRequest {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(
+      f,
+      "[{}]: Commit({:?}, payload: {:?})",
+      self.app_id,
+      self.parts.iter().map(|part| &part.key).collect::<Vec<_>>(),
+      self.payload
+    )
+  }
+}
+
 impl Display for proto::RevertRequest {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
     write!(f, "[{}]: Revert({:?})", self.app_id, self.checkpoint_id)
@@ -383,6 +1235,18 @@ impl Display for proto::CleanupRequest {
   }
 }
 
+impl Display for proto::PinCheckpointRequest {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "[{}]: PinCheckpoint({:?})", self.app_id, self.checkpoint_id)
+  }
+}
+
+impl Display for proto::UnpinCheckpointRequest {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "[{}]: UnpinCheckpoint({:?})", self.app_id, self.checkpoint_id)
+  }
+}
+
 impl Display for proto::ResetRequest {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
     write!(f, "[{}]: Reset()", self.app_id)
@@ -395,8 +1259,336 @@ impl Display for proto::RemoveAppRequest {
   }
 }
 
+impl Display for proto::ForkAppRequest {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(
+      f,
+      "[{}]: ForkApp(checkpoint: {:?}, new app: {:?})",
+      self.source_app_id, self.checkpoint_id, self.new_app_id
+    )
+  }
+}
+
+impl Display for proto::ListAppsRequest {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(
+      f,
+      "ListApps(prefix: {:?}, limit: {}, after: {:?})",
+      self.prefix, self.limit, self.continuation_token
+    )
+  }
+}
+
+impl Display for proto::AppInfoRequest {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "[{}]: AppInfo()", self.app_id)
+  }
+}
+
 impl Display for proto::UploadSnapshotRequest {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
     write!(f, "[{}]: UploadSnapshot()", self.app_id)
   }
 }
+
+impl Display for proto::RestoreSnapshotRequest {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "[{}]: RestoreSnapshot({:?})", self.app_id, self.snapshot_id)
+  }
+}
+
+impl Display for proto::ListSnapshotsRequest {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(
+      f,
+      "[{}]: ListSnapshots(limit: {}, after: {:?})",
+      self.app_id, self.limit, self.continuation_token
+    )
+  }
+}
+
+impl Display for proto::GetSnapshotInfoRequest {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "[{}]: GetSnapshotInfo({:?})", self.app_id, self.snapshot_id)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::auth::{AuthConfig, AuthInterceptor};
+  use crate::file_storage::s3::S3FileStorage;
+  use crate::service::in_memory::InMemoryStateManager;
+  use crate::service::persistent::PersistentStateManager;
+  use crate::service::tests::DirFileStorage;
+  use std::path::Path;
+  use std::sync::Arc;
+  use tonic::service::Interceptor;
+
+  type Service = GrpcService<InMemoryStateManager, S3FileStorage>;
+
+  fn part(key: &str, value: &str) -> proto::Part {
+    proto::Part {
+      key: key.to_owned(),
+      value: value.as_bytes().to_vec(),
+    }
+  }
+
+  // A request with the token, if it's not empty, as the interceptor passes it to the service
+  fn intercepted<T>(interceptor: &mut AuthInterceptor, token: &str, message: T) -> Request<T> {
+    let mut request = Request::new(());
+    if !token.is_empty() {
+      let value = format!("Bearer {}", token).parse().unwrap();
+      request.metadata_mut().insert("authorization", value);
+    }
+    let grants = interceptor
+      .call(request)
+      .unwrap()
+      .extensions()
+      .get::<Arc<auth::Grants>>()
+      .cloned()
+      .unwrap();
+    let mut request = Request::new(message);
+    request.extensions_mut().insert(grants);
+    request
+  }
+
+  fn init_app<M: StateManager>(service: &GrpcService<M, S3FileStorage>, app_id: &str) {
+    let app_id = AppId::new(app_id).unwrap();
+    service.manager.init_app(&app_id, &Default::default()).unwrap();
+  }
+
+  async fn bulk_set<M: StateManager>(
+    service: &GrpcService<M, S3FileStorage>,
+    header: &proto::BulkSetRequest,
+    messages: Vec<Vec<proto::Part>>,
+  ) -> Result<proto::BulkSetResponse, Status> {
+    let mut messages = messages.into_iter().map(|parts| {
+      Ok(proto::BulkSetRequest {
+        parts,
+        ..Default::default()
+      })
+    });
+    let parts = messages.next().unwrap().unwrap().parts;
+    let stream = tokio_stream::iter(messages);
+    let response = service.bulk_set(header, parts, stream).await?;
+    Ok(response.into_inner())
+  }
+
+  async fn check_bulk_set<M: StateManager>(manager: M) {
+    let service = GrpcService::<M, S3FileStorage>::new(manager);
+    let app_id = AppId::new("bulk").unwrap();
+    let options = interface::AppOptions {
+      quota: Some(Quota {
+        max_keys: Some(3),
+        max_value_size: Some(4),
+        ..Default::default()
+      }),
+      ..Default::default()
+    };
+    service.manager.init_app(&app_id, &options).unwrap();
+    let (etag, mut receiver) = service
+      .manager
+      .with_app(&app_id, |app| {
+        app.set(vec![KeyValue {
+          key: "a".to_owned(),
+          value: b"0".to_vec(),
+        }])
+        .unwrap();
+        (app.etag(), app.watch())
+      })
+      .unwrap();
+    let header = |etag: &str| proto::BulkSetRequest {
+      app_id: "bulk".to_owned(),
+      etag: etag.to_owned(),
+      create_checkpoint: true,
+      checkpoint_payload: "loaded".to_owned(),
+      ..Default::default()
+    };
+    let unchanged = |service: &GrpcService<M, S3FileStorage>| {
+      service
+        .manager
+        .with_app(&app_id, |app| {
+          assert_eq!(app.etag(), etag);
+          assert!(app.get_checkpoints().unwrap().is_empty());
+        })
+        .unwrap();
+    };
+
+    let status = bulk_set(&service, &header("stale"), vec![vec![part("b", "1")]]).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+    // Rejected while the parts are received
+    let messages = vec![vec![part("b", "1")], vec![part("c", "12345")]];
+    let status = bulk_set(&service, &header(&etag), messages).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+    let messages = vec![vec![part("b", "1")], vec![part("", "1")]];
+    let status = bulk_set(&service, &header(&etag), messages).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    let messages = vec![vec![part("b", "1"), part("c", "1")], vec![part("d", "1"), part("e", "1")]];
+    let status = bulk_set(&service, &header(&etag), messages).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+    // Rejected once all the parts are received, since the app would have 4 keys
+    let messages = vec![vec![part("b", "1")], vec![part("c", "1"), part("d", "1")]];
+    let status = bulk_set(&service, &header(&etag), messages).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+    unchanged(&service);
+    assert!(receiver.try_recv().is_err());
+
+    let messages = vec![vec![part("b", "1"), part("a", "1")], vec![], vec![part("b", "2")]];
+    let response = bulk_set(&service, &header(&etag), messages).await.unwrap();
+    assert_eq!(response.parts_written, 3);
+    service
+      .manager
+      .with_app(&app_id, |app| {
+        assert_eq!(response.etag, app.etag());
+        let checkpoints = app.get_checkpoints().unwrap();
+        assert_eq!(checkpoints.len(), 1);
+        assert_eq!(checkpoints[0].id, response.checkpoint_id);
+        assert_eq!(checkpoints[0].payload, "loaded");
+        let values: Vec<_> = app.get(&["a", "b"]).unwrap().into_iter().map(|part| part.value).collect();
+        assert_eq!(values, [b"1", b"2"]);
+      })
+      .unwrap();
+    // Watchers see all the parts at once
+    let event = receiver.try_recv().unwrap();
+    assert_eq!(event.changes.len(), 3);
+    assert!(receiver.try_recv().is_err());
+
+    // Only new keys count, so an app at its quota can be overwritten with more parts than keys
+    let messages = vec![vec![part("c", "1"), part("a", "2")], vec![part("b", "3"), part("a", "4")]];
+    let response = bulk_set(&service, &header(&response.etag), messages).await.unwrap();
+    assert_eq!(response.parts_written, 4);
+    let messages = vec![vec![part("a", "5"), part("d", "1")]];
+    let status = bulk_set(&service, &header(&response.etag), messages).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+    assert!(status.message().contains("would have 4 keys"), "{}", status.message());
+  }
+
+  #[tokio::test]
+  async fn test_init_app_quota() {
+    let config = AuthConfig::parse(
+      r#"{"tokens": [
+        {"name": "writer", "token": "w", "apps": {"*": "write"}},
+        {"name": "admin", "token": "a", "apps": {"*": "admin"}}
+      ]}"#,
+    )
+    .unwrap();
+    let mut interceptor = AuthInterceptor::new(Some(config));
+    let service = Service::new(InMemoryStateManager::default());
+    let mut init = |token: &str, quota: Option<proto::Quota>| {
+      let message = proto::InitAppRequest {
+        app_id: "app".to_owned(),
+        quota,
+        ..Default::default()
+      };
+      intercepted(&mut interceptor, token, message)
+    };
+    let quota = proto::Quota {
+      max_keys: 1_000_000,
+      ..Default::default()
+    };
+
+    StateManagerService::init_app(&service, init("w", None)).await.unwrap();
+    let status = StateManagerService::init_app(&service, init("w", Some(quota.clone())))
+      .await
+      .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::PermissionDenied);
+    StateManagerService::init_app(&service, init("a", Some(quota))).await.unwrap();
+    let max_keys = service
+      .manager
+      .with_app(&AppId::new("app").unwrap(), |app| app.quota().max_keys)
+      .unwrap();
+    assert_eq!(max_keys, Some(1_000_000));
+  }
+
+  #[tokio::test]
+  async fn test_bulk_set() {
+    let dir = tempfile::tempdir().unwrap();
+    check_bulk_set(InMemoryStateManager::default()).await;
+    check_bulk_set(PersistentStateManager::new(dir.path(), Backend::Filesystem)).await;
+  }
+
+  #[tokio::test]
+  async fn test_list_snapshots() {
+    let dir = tempfile::tempdir().unwrap();
+    let manager = PersistentStateManager::new(dir.path().join("db"), Backend::Filesystem);
+    let app_id = AppId::new("app").unwrap();
+    manager.init_app(&app_id, &Default::default()).unwrap();
+    manager
+      .with_app(&app_id, |app| {
+        app.set(vec![KeyValue {
+          key: "manifest.json".to_owned(),
+          value: b"{}".to_vec(),
+        }])
+        .unwrap();
+        app.create_checkpoint("").unwrap();
+      })
+      .unwrap();
+    let storage = DirFileStorage {
+      root: dir.path().join("storage"),
+    };
+    for id in ["s1", "s2", "s3"] {
+      let prefix = snapshot_prefix(&app_id, id).unwrap();
+      manager.store_snapshot(&app_id, &storage, &prefix).await.unwrap();
+    }
+    // A checkpoint file named like a snapshot manifest doesn't make a snapshot
+    storage
+      .upload_buffer(b"{}", Path::new("app/s1/checkpoints/0/manifest.json"))
+      .await
+      .unwrap();
+    let service = GrpcService::new(manager).with_snapshot_storage(storage);
+    let mut interceptor = AuthInterceptor::new(None);
+    let mut list = |continuation_token: &str| {
+      let message = proto::ListSnapshotsRequest {
+        app_id: "app".to_owned(),
+        limit: 2,
+        continuation_token: continuation_token.to_owned(),
+      };
+      intercepted(&mut interceptor, "", message)
+    };
+    let ids = |response: &proto::ListSnapshotsResponse| -> Vec<_> {
+      response.snapshots.iter().map(|snapshot| snapshot.id.clone()).collect()
+    };
+
+    let response = StateManagerService::list_snapshots(&service, list(""))
+      .await
+      .unwrap()
+      .into_inner();
+    assert_eq!(ids(&response), ["s1", "s2"]);
+    assert_eq!(response.continuation_token, "s2");
+    let response = StateManagerService::list_snapshots(&service, list("s2"))
+      .await
+      .unwrap()
+      .into_inner();
+    assert_eq!(ids(&response), ["s3"]);
+    assert_eq!(response.continuation_token, "");
+  }
+
+  #[tokio::test]
+  async fn test_remove_app_without_auth() {
+    let service = Service::new(InMemoryStateManager::default());
+    init_app(&service, "app");
+    let mut interceptor = AuthInterceptor::new(None);
+    let remove = |admin_token: &str| {
+      #[allow(deprecated)]
+      proto::RemoveAppRequest {
+        app_id: "app".to_owned(),
+        admin_token: admin_token.to_owned(),
+      }
+    };
+
+    let request = intercepted(&mut interceptor, "", remove(""));
+    let status = StateManagerService::remove_app(&service, request).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::PermissionDenied);
+    assert_eq!(service.manager.list_apps().unwrap(), [AppId::new("app").unwrap()]);
+
+    let request = intercepted(&mut interceptor, "", remove(ADMIN_TOKEN));
+    StateManagerService::remove_app(&service, request).await.unwrap();
+    assert!(service.manager.list_apps().unwrap().is_empty());
+
+    init_app(&service, "app");
+    let mut insecure = AuthInterceptor::new(None).with_insecure_admin();
+    let request = intercepted(&mut insecure, "", remove(""));
+    StateManagerService::remove_app(&service, request).await.unwrap();
+  }
+}
@@ -1,13 +1,20 @@
+// The gRPC handlers and interceptors return `tonic::Status`, which is large
+#![allow(clippy::result_large_err)]
+
+use auth::{AuthConfig, AuthInterceptor};
 use clap::Parser;
 use file_storage::s3::S3FileStorage;
 use grpc::GrpcService;
-use log::info;
+use log::{info, warn};
 use proto::state_manager_service_server::StateManagerServiceServer;
 use s3::{creds::Credentials, Bucket, Region};
+use service::in_memory::InMemoryStateManager;
+use service::interface::StateManager;
 use service::persistent::PersistentStateManager;
-use storage::filesystem::FilesystemStorage;
+use service::quota::Quota;
 use tonic::transport::Server;
 
+mod auth;
 mod file_storage;
 mod grpc;
 mod service;
@@ -18,6 +25,23 @@ mod proto {
 }
 mod utils;
 
+// Default backend of new apps. Existing apps keep the backend they were created with
+#[derive(clap::ArgEnum, Clone, Copy, Debug)]
+enum Backend {
+  Filesystem,
+  Rocksdb,
+  // Nothing is persisted, useful for tests
+  Memory,
+}
+
+#[derive(clap::ArgEnum, Clone, Copy, Debug)]
+enum S3AddressingStyle {
+  // https://endpoint/bucket/key
+  Path,
+  // https://bucket.endpoint/key
+  VirtualHost,
+}
+
 #[derive(Parser, Debug)]
 #[clap(version)]
 struct Args {
@@ -27,17 +51,66 @@ struct Args {
   #[clap(long, env, default_value = "/run/state-manager")]
   db_path: String,
 
+  #[clap(long, env, arg_enum, default_value = "filesystem")]
+  backend: Backend,
+
   #[clap(env, default_value_t = log::LevelFilter::Info)]
   log_level: log::LevelFilter,
 
   #[clap(env)]
   s3_endpoint: Option<String>,
 
+  /// Snapshots are stored only if either this or `s3_endpoint` is set
+  #[clap(long, env)]
+  s3_bucket: Option<String>,
+
+  #[clap(long, env, default_value = "us-east-1")]
+  s3_region: String,
+
+  /// Key prefix of all the snapshots in the bucket
+  #[clap(long, env, default_value = "snapshots")]
+  s3_prefix: String,
+
+  #[clap(long, env, arg_enum, default_value = "path")]
+  s3_addressing_style: S3AddressingStyle,
+
   #[clap(env)]
   aws_access_key_id: Option<String>,
 
   #[clap(env)]
   aws_secret_access_key: Option<String>,
+
+  /// JSON file with tokens and their scopes. Authorization is disabled if neither it nor
+  /// `auth_tokens` are set
+  #[clap(long, env)]
+  auth_config: Option<String>,
+
+  /// The same as the contents of `auth_config`, for environments where a file isn't convenient
+  #[clap(long, env, conflicts_with = "auth-config")]
+  auth_tokens: Option<String>,
+
+  /// Gives every client admin access to all the apps if the authorization is disabled.
+  /// Otherwise such clients can't call admin requests, except `RemoveApp` with the legacy token
+  #[clap(long)]
+  insecure_no_auth: bool,
+
+  /// Default quotas of apps, which can override them with `InitApp`. Unlimited if not set
+  #[clap(long, env)]
+  max_keys: Option<u64>,
+
+  /// Total size of the values of an app in bytes
+  #[clap(long, env)]
+  max_bytes: Option<u64>,
+
+  #[clap(long, env)]
+  max_value_size: Option<u64>,
+
+  #[clap(long, env)]
+  max_checkpoints: Option<u64>,
+
+  /// Directory for BulkSet parts being received, the system temporary directory if not set
+  #[clap(long, env)]
+  spool_path: Option<String>,
 }
 
 fn setup_logger(args: &Args) -> Result<(), fern::InitError> {
@@ -49,40 +122,108 @@ fn setup_logger(args: &Args) -> Result<(), fern::InitError> {
   Ok(())
 }
 
-fn build_s3_storage(args: &Args) -> Option<S3FileStorage> {
-  if let Some(s3_endpoint) = &args.s3_endpoint {
-    let region = Region::Custom {
+fn build_s3_storage(args: &Args) -> Result<Option<S3FileStorage>, Box<dyn std::error::Error>> {
+  if args.s3_endpoint.is_none() && args.s3_bucket.is_none() {
+    return Ok(None);
+  }
+  let region = match &args.s3_endpoint {
+    Some(s3_endpoint) => Region::Custom {
       endpoint: s3_endpoint.clone(),
-      region: "us-east-1".to_string(),
-    };
-    let creds = Credentials::from_env().unwrap();
-    let bucket = Bucket::new("state-manager-snapshots", region, creds)
-      .unwrap()
-      .with_path_style();
-    Some(S3FileStorage::new(bucket))
-  } else {
-    None
+      region: args.s3_region.clone(),
+    },
+    None => args.s3_region.parse()?,
+  };
+  // Falls back to the environment, profile and instance metadata if the keys aren't passed
+  let creds = Credentials::new(
+    args.aws_access_key_id.as_deref(),
+    args.aws_secret_access_key.as_deref(),
+    None,
+    None,
+    None,
+  )
+  .map_err(|err| format!("Couldn't find S3 credentials: {}", err))?;
+  let bucket_name = args.s3_bucket.as_deref().unwrap_or("state-manager-snapshots");
+  let mut bucket = Bucket::new(bucket_name, region, creds)?;
+  if let S3AddressingStyle::Path = args.s3_addressing_style {
+    bucket = bucket.with_path_style();
   }
+  info!(
+    "Storing snapshots in s3://{}/{} at {}",
+    bucket.name(),
+    args.s3_prefix,
+    bucket.url()
+  );
+  Ok(Some(S3FileStorage::new(bucket).with_prefix(&args.s3_prefix)))
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-  let args = Args::parse();
-  setup_logger(&args)?;
-
+async fn serve(
+  manager: impl StateManager + 'static,
+  args: &Args,
+) -> Result<(), Box<dyn std::error::Error>> {
   let addr = format!("0.0.0.0:{}", &args.port).parse()?;
-  let mut service = GrpcService::new(PersistentStateManager::<FilesystemStorage>::new(
-    &args.db_path,
-  ));
-  if let Some(storage) = build_s3_storage(&args) {
+  let mut service = GrpcService::new(manager);
+  if let Some(storage) = build_s3_storage(args)? {
     service = service.with_snapshot_storage(storage);
   }
+  if let Some(spool_path) = &args.spool_path {
+    service = service.with_spool_dir(spool_path);
+  }
+
+  let auth_config = match (&args.auth_config, &args.auth_tokens) {
+    (Some(path), _) => Some(AuthConfig::load(path)?),
+    (None, Some(contents)) => Some(AuthConfig::parse(contents)?),
+    (None, None) if args.insecure_no_auth => {
+      warn!("Authorization is disabled, every client has admin access to all the apps");
+      None
+    }
+    (None, None) => {
+      warn!("Authorization is disabled, every client has write access to all the apps");
+      None
+    }
+  };
+  let mut interceptor = AuthInterceptor::new(auth_config);
+  if args.insecure_no_auth {
+    interceptor = interceptor.with_insecure_admin();
+  }
 
   let on_finish = Server::builder()
-    .add_service(StateManagerServiceServer::new(service))
+    .add_service(StateManagerServiceServer::with_interceptor(
+      service,
+      interceptor,
+    ))
     .serve(addr);
   info!("Listening on {}", addr);
 
   on_finish.await?;
   Ok(())
 }
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+  let args = Args::parse();
+  setup_logger(&args)?;
+
+  info!("Using {:?} backend", args.backend);
+  let quota = Quota {
+    max_keys: args.max_keys,
+    max_bytes: args.max_bytes,
+    max_value_size: args.max_value_size,
+    max_checkpoints: args.max_checkpoints,
+  };
+  match args.backend {
+    Backend::Filesystem => {
+      let manager = PersistentStateManager::new(&args.db_path, storage::any::Backend::Filesystem)
+        .with_default_quota(quota);
+      serve(manager, &args).await
+    }
+    Backend::Rocksdb => {
+      let manager = PersistentStateManager::new(&args.db_path, storage::any::Backend::Rocksdb)
+        .with_default_quota(quota);
+      serve(manager, &args).await
+    }
+    Backend::Memory => {
+      let manager = InMemoryStateManager::default().with_default_quota(quota);
+      serve(manager, &args).await
+    }
+  }
+}
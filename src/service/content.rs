@@ -0,0 +1,167 @@
+use super::interface::Checkpoint;
+use crate::types::{KeyRange, KeyValue, Result};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+
+// Number of keys read at once when the contents are hashed in full
+const PAGE_SIZE: usize = 10_000;
+
+/// Number of keys and hash of the contents of an app. The hash is the sum modulo 2^256 of
+/// the SHA-256 of every length-prefixed key and value, so it doesn't depend on the order
+/// of the writes and is updated from the changed keys alone.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ContentHash {
+  pub keys: u64,
+  // Little-endian 64-bit limbs
+  sum: [u64; 4],
+}
+
+fn entry_hash(key: &str, value: &[u8]) -> [u64; 4] {
+  let mut hasher = Sha256::new();
+  // Lengths make the encoding unambiguous
+  hasher.update((key.len() as u64).to_le_bytes());
+  hasher.update(key);
+  hasher.update((value.len() as u64).to_le_bytes());
+  hasher.update(value);
+  from_be_bytes(&hasher.finalize().into())
+}
+
+// Limbs of a big-endian number
+fn from_be_bytes(bytes: &[u8; 32]) -> [u64; 4] {
+  let mut limbs = [0; 4];
+  for (i, limb) in limbs.iter_mut().enumerate() {
+    let start = 24 - i * 8;
+    *limb = u64::from_be_bytes(bytes[start..start + 8].try_into().unwrap());
+  }
+  limbs
+}
+
+impl ContentHash {
+  #[cfg(test)]
+  pub fn of<'a>(entries: impl IntoIterator<Item = (&'a str, &'a [u8])>) -> Self {
+    let mut result = Self::default();
+    for (key, value) in entries {
+      result.add(key, value);
+    }
+    result
+  }
+
+  // Hashes all the contents, `scan` reads them like `AppStateManager::scan`
+  pub fn measure(scan: impl Fn(&KeyRange, usize) -> Result<Vec<KeyValue>>) -> Result<Self> {
+    let mut result = Self::default();
+    let mut range = KeyRange::default();
+    loop {
+      let page = scan(&range, PAGE_SIZE)?;
+      for part in &page {
+        result.add(&part.key, &part.value);
+      }
+      match page.last() {
+        // The smallest key greater than the last one
+        Some(last) if page.len() == PAGE_SIZE => range.start = format!("{}\0", last.key),
+        _ => return Ok(result),
+      }
+    }
+  }
+
+  // Recorded when the checkpoint was created, `None` for checkpoints of older versions
+  pub fn of_checkpoint(checkpoint: &Checkpoint) -> Option<Self> {
+    let bytes = hex::decode(checkpoint.content_hash.as_ref()?).ok()?;
+    Some(Self {
+      keys: checkpoint.key_count?,
+      sum: from_be_bytes(&bytes.try_into().ok()?),
+    })
+  }
+
+  pub fn add(&mut self, key: &str, value: &[u8]) {
+    let mut carry = false;
+    for (limb, other) in self.sum.iter_mut().zip(entry_hash(key, value)) {
+      let (sum, overflow) = limb.overflowing_add(other);
+      let (sum, carry_overflow) = sum.overflowing_add(carry as u64);
+      *limb = sum;
+      carry = overflow || carry_overflow;
+    }
+    self.keys += 1;
+  }
+
+  pub fn remove(&mut self, key: &str, value: &[u8]) {
+    let mut borrow = false;
+    for (limb, other) in self.sum.iter_mut().zip(entry_hash(key, value)) {
+      let (difference, overflow) = limb.overflowing_sub(other);
+      let (difference, borrow_overflow) = difference.overflowing_sub(borrow as u64);
+      *limb = difference;
+      borrow = overflow || borrow_overflow;
+    }
+    self.keys -= 1;
+  }
+
+  // Contents after the keys are changed to the new values, `None` for deleted keys.
+  // The last change of a key wins, `get` reads the current values like `AppStateManager::get`
+  pub fn after_write<'a>(
+    &self,
+    get: impl FnOnce(&[&str]) -> Result<Vec<KeyValue>>,
+    changes: impl IntoIterator<Item = (&'a str, Option<&'a [u8]>)>,
+  ) -> Result<Self> {
+    let changes: BTreeMap<_, _> = changes.into_iter().collect();
+    let keys: Vec<_> = changes.keys().copied().collect();
+    let old: HashMap<_, _> = get(&keys)?
+      .into_iter()
+      .map(|part| (part.key, part.value))
+      .collect();
+
+    let mut result = *self;
+    for (key, new) in changes {
+      if let Some(old) = old.get(key) {
+        result.remove(key, old);
+      }
+      if let Some(new) = new {
+        result.add(key, new);
+      }
+    }
+    Ok(result)
+  }
+
+  // Hex of the sum as a big-endian number
+  pub fn hex(&self) -> String {
+    let bytes: Vec<u8> = self.sum.iter().rev().flat_map(|limb| limb.to_be_bytes()).collect();
+    hex::encode(bytes)
+  }
+}
+
+// Changes of `ContentHash::after_write` setting the parts
+pub fn set_values(parts: &[KeyValue]) -> impl Iterator<Item = (&str, Option<&[u8]>)> {
+  parts
+    .iter()
+    .map(|part| (part.key.as_str(), Some(part.value.as_slice())))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_content_hash() {
+    let a = ContentHash::of([("a", b"1".as_slice()), ("b", b"2".as_slice())]);
+    let b = ContentHash::of([("b", b"2".as_slice()), ("a", b"1".as_slice())]);
+    assert_eq!(a, b);
+    assert_eq!(a.keys, 2);
+    assert_ne!(a, ContentHash::of([("a", b"1".as_slice()), ("b", b"3".as_slice())]));
+    assert_ne!(a, ContentHash::of([("a", b"12".as_slice()), ("b", b"".as_slice())]));
+
+    let mut c = a;
+    c.remove("b", b"2");
+    c.add("b", b"3");
+    assert_eq!(c, ContentHash::of([("a", b"1".as_slice()), ("b", b"3".as_slice())]));
+    c.remove("a", b"1");
+    c.remove("b", b"3");
+    assert_eq!(c, ContentHash::default());
+    assert_eq!(ContentHash::default().hex(), "0".repeat(64));
+
+    let checkpoint = Checkpoint {
+      key_count: Some(a.keys),
+      content_hash: Some(a.hex()),
+      ..Default::default()
+    };
+    assert_eq!(ContentHash::of_checkpoint(&checkpoint), Some(a));
+    assert_eq!(ContentHash::of_checkpoint(&Default::default()), None);
+  }
+}
@@ -0,0 +1,90 @@
+use crate::types::{Bytes, KeyRange, KeyValue, Result};
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+
+// Number of keys read from a side at once
+const PAGE_SIZE: usize = 1000;
+
+/// Difference of a key between two states. `old` is `None` for added keys
+/// and `new` is `None` for removed ones.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyDiff {
+  pub key: String,
+  pub old: Option<Bytes>,
+  pub new: Option<Bytes>,
+}
+
+// Reads a range of a checkpoint, or of HEAD if it's `None`, page by page
+struct Pages<'a> {
+  checkpoint_id: Option<&'a str>,
+  range: KeyRange,
+  page: VecDeque<KeyValue>,
+  done: bool,
+}
+
+impl<'a> Pages<'a> {
+  fn new(checkpoint_id: Option<&'a str>, range: &KeyRange) -> Self {
+    Self {
+      checkpoint_id,
+      range: range.clone(),
+      page: VecDeque::new(),
+      done: false,
+    }
+  }
+
+  fn peek(
+    &mut self,
+    scan: &mut impl FnMut(Option<&str>, &KeyRange, usize) -> Result<Vec<KeyValue>>,
+  ) -> Result<Option<&KeyValue>> {
+    if self.page.is_empty() && !self.done {
+      let page = scan(self.checkpoint_id, &self.range, PAGE_SIZE)?;
+      self.done = page.len() < PAGE_SIZE;
+      if let Some(last) = page.last() {
+        // The smallest key greater than the last one
+        self.range.start = format!("{}\0", last.key);
+      }
+      self.page = page.into();
+    }
+    Ok(self.page.front())
+  }
+
+  fn next(&mut self) -> Option<KeyValue> {
+    self.page.pop_front()
+  }
+}
+
+/// Returns at most `limit` differences between checkpoints in the range ordered
+/// by key. `scan` reads a checkpoint, or HEAD if it's `None`, like `AppStateManager::scan_at`.
+pub fn diff(
+  mut scan: impl FnMut(Option<&str>, &KeyRange, usize) -> Result<Vec<KeyValue>>,
+  from: Option<&str>,
+  to: Option<&str>,
+  range: &KeyRange,
+  limit: usize,
+) -> Result<Vec<KeyDiff>> {
+  let mut old = Pages::new(from, range);
+  let mut new = Pages::new(to, range);
+  let mut result = Vec::new();
+  while result.len() < limit {
+    let order = match (old.peek(&mut scan)?, new.peek(&mut scan)?) {
+      (None, None) => break,
+      (Some(_), None) => Ordering::Less,
+      (None, Some(_)) => Ordering::Greater,
+      (Some(old), Some(new)) => old.key.cmp(&new.key),
+    };
+    let (old, new) = match order {
+      Ordering::Less => (old.next(), None),
+      Ordering::Greater => (None, new.next()),
+      Ordering::Equal => (old.next(), new.next()),
+    };
+    if old.as_ref().map(|part| &part.value) == new.as_ref().map(|part| &part.value) {
+      continue;
+    }
+    result.push(KeyDiff {
+      key: old.as_ref().or(new.as_ref()).unwrap().key.clone(),
+      old: old.map(|part| part.value),
+      new: new.map(|part| part.value),
+    });
+  }
+  Ok(result)
+}
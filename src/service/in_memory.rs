@@ -1,34 +1,207 @@
-use super::interface::{AppStateManager, Checkpoint, StateManager};
+use super::interface::{
+  pinned_checkpoint_error, AppInfo, AppOptions, AppStateManager, Checkpoint, Committed,
+  CreatedCheckpoint, SnapshotInfo, StateManager,
+};
+use super::content::{self, ContentHash};
+use super::quota::{Quota, Usage};
+use super::retention::Retention;
+use super::watch::{self, ChangeReceiver, Watchers};
 use crate::file_storage::interface::FileStorage;
-use crate::types::{Bytes, Error, KeyValue, Result};
-use dashmap::DashMap;
+use crate::types::{validate_key, AppId, Bytes, Error, KeyRange, KeyValue, Result};
+use crate::utils::spool::{Spool, CHUNK_BYTES, CHUNK_KEYS};
+use crate::utils::random_string;
+use dashmap::{mapref::entry::Entry, DashMap};
 use log::info;
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 
-type KVMap = HashMap<String, Bytes>;
+// `None` marks a key deleted since the last checkpoint
+type Changes = BTreeMap<String, Option<Bytes>>;
 
 #[derive(Default, Debug)]
 pub struct InMemoryStateManager {
+  // Quota of apps which don't override it
+  default_quota: Quota,
   apps: DashMap<String, InMemoryAppStateManager>,
 }
 
-#[derive(Default, Debug)]
+impl InMemoryStateManager {
+  pub fn with_default_quota(mut self, quota: Quota) -> Self {
+    self.default_quota = quota;
+    self
+  }
+}
+
+#[derive(Debug)]
 pub struct InMemoryAppStateManager {
-  current: KVMap,
+  instance_id: String,
+  current: Changes,
   checkpoints: Vec<AppCheckpoint>,
-  modifications_number: u32,
+  modifications_number: u64,
+  last_write_at: Option<chrono::DateTime<chrono::Utc>>,
+  retention: Retention,
+  // The default quota with the overrides of the app
+  quota: Quota,
+  // Measured on the first write if the quota limits it, unknown after a revert or a reset
+  usage: Option<Usage>,
+  // Kept up to date by writes and restored with the checkpoints
+  content_hash: ContentHash,
+  watchers: Watchers,
 }
 
 #[derive(Default, Debug)]
 struct AppCheckpoint {
-  id: String,
-  payload: String,
-  values: KVMap,
+  checkpoint: Checkpoint,
+  // Changes since the previous checkpoint
+  changes: Changes,
+  content_hash: ContentHash,
+}
+
+fn snapshots_unsupported() -> Error {
+  Error::Unimplemented("Snapshots are not supported by the in-memory backend".to_owned())
+}
+
+// Merges the changes of checkpoints ordered from the newest, newer ones take precedence
+fn scan_layers<'a>(
+  layers: impl Iterator<Item = &'a Changes>,
+  range: &KeyRange,
+  limit: usize,
+) -> Vec<KeyValue> {
+  let mut layers: Vec<_> = layers.map(|changes| range.iter(changes).peekable()).collect();
+
+  let mut result = Vec::new();
+  while result.len() < limit {
+    let key = match layers.iter_mut().filter_map(|layer| layer.peek().map(|(key, _)| *key)).min() {
+      Some(key) => key,
+      None => break,
+    };
+    // The newest layer changing the key wins, the others are skipped
+    let mut value = None;
+    for layer in &mut layers {
+      if let Some((_, change)) = layer.next_if(|(layer_key, _)| *layer_key == key) {
+        value.get_or_insert(change);
+      }
+    }
+    if let Some(Some(value)) = value {
+      result.push(KeyValue {
+        key: key.clone(),
+        value: value.clone(),
+      });
+    }
+  }
+  result
 }
 
 impl InMemoryAppStateManager {
-  pub fn new() -> Self {
-    Self::default()
+  pub fn new(retention: Retention, quota: Quota) -> Self {
+    Self {
+      // Nothing survives a restart, so every instance is new
+      instance_id: random_string(8),
+      current: Default::default(),
+      checkpoints: Vec::new(),
+      modifications_number: 0,
+      last_write_at: None,
+      retention,
+      quota,
+      usage: None,
+      content_hash: ContentHash::default(),
+      watchers: Watchers::default(),
+    }
+  }
+
+  // Creates an app with the only checkpoint equal to the checkpoint of this one
+  fn fork(&self, checkpoint_id: &str) -> Result<Self> {
+    let index = self.checkpoint_index(checkpoint_id)?;
+    let layers = self.checkpoints[..=index].iter().rev().map(|checkpoint| &checkpoint.changes);
+    let changes = scan_layers(layers, &KeyRange::default(), usize::MAX)
+      .into_iter()
+      .map(|part| (part.key, Some(part.value)))
+      .collect();
+    let mut result = Self::new(self.retention, self.quota);
+    // Ids of new checkpoints are modification numbers, so they must not repeat the copied one
+    result.modifications_number = self.modifications_number;
+    result.content_hash = self.checkpoints[index].content_hash;
+    result.checkpoints.push(AppCheckpoint {
+      checkpoint: Checkpoint {
+        pinned: false,
+        ..self.checkpoints[index].checkpoint.clone()
+      },
+      changes,
+      content_hash: self.checkpoints[index].content_hash,
+    });
+    Ok(result)
+  }
+
+  fn bump_modifications_number(&mut self) {
+    self.modifications_number += 1;
+    self.last_write_at = Some(chrono::Utc::now());
+  }
+
+  // Usage after the changes, or `None` if the quota doesn't limit it
+  fn usage_after<'a>(
+    &mut self,
+    changes: impl IntoIterator<Item = (&'a str, Option<u64>)>,
+  ) -> Result<Option<Usage>> {
+    if !self.quota.limits_usage() {
+      return Ok(None);
+    }
+    let usage = match self.usage {
+      Some(usage) => usage,
+      None => {
+        let usage = Usage::measure(self)?;
+        self.usage = Some(usage);
+        usage
+      }
+    };
+    usage.after_write(self, changes).map(Some)
+  }
+
+  // Id of the next checkpoint and the ones evicted once it's created `now`.
+  // Fails if the quota doesn't allow one more checkpoint
+  fn next_checkpoint(&self, now: chrono::DateTime<chrono::Utc>) -> Result<(String, Vec<String>)> {
+    let new_id = (self.modifications_number + 1).to_string();
+    let checkpoints = self.get_checkpoints()?;
+    let evicted = self.retention.evicted(&checkpoints, &new_id, now);
+    self
+      .quota
+      .check_checkpoints(checkpoints.len() + 1 - evicted.len())?;
+    Ok((new_id, evicted))
+  }
+
+  fn checkpoint_index(&self, id: &str) -> Result<usize> {
+    self
+      .checkpoints
+      .iter()
+      .position(|checkpoint| checkpoint.checkpoint.id == id)
+      .ok_or_else(|| Error::NotFound(format!("Checkpoint with id {} does not exist", id)))
+  }
+
+  // The changes of removed checkpoints are passed to the next remaining one
+  fn drop_checkpoints(&mut self, remove: impl Fn(usize, &Checkpoint) -> bool) {
+    let mut removed_changes = Changes::new();
+    let mut removed = 0;
+    let mut kept: Vec<AppCheckpoint> = Vec::new();
+    for (i, mut checkpoint) in std::mem::take(&mut self.checkpoints).into_iter().enumerate() {
+      if remove(i, &checkpoint.checkpoint) {
+        removed_changes.extend(checkpoint.changes);
+        removed += 1;
+        continue;
+      }
+      if !removed_changes.is_empty() {
+        removed_changes.extend(std::mem::take(&mut checkpoint.changes));
+        checkpoint.changes = std::mem::take(&mut removed_changes);
+      }
+      if kept.is_empty() {
+        checkpoint.changes.retain(|_, change| change.is_some());
+      }
+      kept.push(checkpoint);
+    }
+    info!("Dropped {} checkpoints", removed);
+    self.checkpoints = kept;
+  }
+
+  // Changes from the newest to the oldest, their union on top of each other is the current state
+  fn layers(&self) -> impl Iterator<Item = &Changes> {
+    std::iter::once(&self.current).chain(self.checkpoints.iter().rev().map(|checkpoint| &checkpoint.changes))
   }
 }
 
@@ -36,40 +209,87 @@ impl InMemoryAppStateManager {
 impl StateManager for InMemoryStateManager {
   type AppStateManager = InMemoryAppStateManager;
 
-  fn init_app(&self, id: &str) -> Result<()> {
-    self
-      .apps
-      .entry(id.to_owned())
-      .or_insert_with(InMemoryAppStateManager::new);
+  // There is no storage, so the backend option doesn't matter.
+  // Checkpoints are kept until cleaned up unless the app chooses another retention
+  fn init_app(&self, id: &AppId, options: &AppOptions) -> Result<()> {
+    let mut app = self.apps.entry(id.to_string()).or_insert_with(|| {
+      let retention = options.retention.unwrap_or(Retention::KeepAll);
+      InMemoryAppStateManager::new(retention, self.default_quota)
+    });
+    if let Some(retention) = options.retention {
+      app.retention = retention;
+    }
+    if let Some(quota) = &options.quota {
+      app.quota = self.default_quota.with_overrides(quota);
+    }
     Ok(())
   }
 
   fn with_app<Out>(
     &self,
-    id: &str,
+    id: &AppId,
     f: impl FnOnce(&mut Self::AppStateManager) -> Out,
   ) -> Result<Out> {
-    if let Some(mut app) = self.apps.get_mut(id) {
+    if let Some(mut app) = self.apps.get_mut(id.as_str()) {
       Ok(f(&mut app))
     } else {
       Err(Error::NotFound(format!("Unknown app: {}", id)))
     }
   }
 
-  fn drop_app(&self, id: &str) -> Result<()> {
-    match self.apps.remove(id) {
+  fn drop_app(&self, id: &AppId) -> Result<()> {
+    match self.apps.remove(id.as_str()) {
       Some(_) => Ok(()),
       None => Err(Error::NotFound(format!("App {} not found", id))),
     }
   }
 
+  fn fork_app(&self, source_id: &AppId, checkpoint_id: &str, id: &AppId) -> Result<()> {
+    // The source is released before the new app is inserted, since the map could deadlock
+    let app = self.with_app(source_id, |app| app.fork(checkpoint_id))??;
+    match self.apps.entry(id.to_string()) {
+      Entry::Occupied(_) => Err(Error::AlreadyExists(format!("App {} already exists", id))),
+      Entry::Vacant(entry) => {
+        entry.insert(app);
+        Ok(())
+      }
+    }
+  }
+
+  fn list_apps(&self) -> Result<Vec<AppId>> {
+    let mut ids = self
+      .apps
+      .iter()
+      .map(|app| AppId::new(app.key()))
+      .collect::<Result<Vec<_>>>()?;
+    ids.sort();
+    Ok(ids)
+  }
+
   async fn store_snapshot(
     &self,
-    _app_id: &str,
+    _app_id: &AppId,
+    _storage: &impl FileStorage,
+    _prefix: &std::path::Path,
+  ) -> Result<()> {
+    Err(snapshots_unsupported())
+  }
+
+  async fn snapshot_info(
+    &self,
+    _storage: &impl FileStorage,
+    _prefix: &std::path::Path,
+  ) -> Result<SnapshotInfo> {
+    Err(snapshots_unsupported())
+  }
+
+  async fn restore_snapshot(
+    &self,
+    _app_id: &AppId,
     _storage: &impl FileStorage,
     _prefix: &std::path::Path,
   ) -> Result<()> {
-    unimplemented!();
+    Err(snapshots_unsupported())
   }
 }
 
@@ -79,65 +299,154 @@ impl AppStateManager for InMemoryAppStateManager {
     let mut result = Vec::new();
     for key in keys {
       let key = key.as_ref();
-      if let Some(value) = self.current.get(key) {
+      let change = self.layers().find_map(|changes| changes.get(key));
+      if let Some(Some(value)) = change {
         result.push(KeyValue {
           key: key.to_owned(),
           value: value.clone(),
         });
-        continue;
-      }
-      if let Some(last_checkpoint) = self.checkpoints.last() {
-        if let Some(value) = last_checkpoint.values.get(key) {
-          result.push(KeyValue {
-            key: key.to_owned(),
-            value: value.clone(),
-          });
-        }
       }
     }
     Ok(result)
   }
 
+  fn scan(&self, range: &KeyRange, limit: usize) -> Result<Vec<KeyValue>> {
+    Ok(scan_layers(self.layers(), range, limit))
+  }
+
   fn set(&mut self, parts: Vec<KeyValue>) -> Result<()> {
-    self.modifications_number += 1;
+    parts.iter().try_for_each(|part| validate_key(&part.key))?;
+    self.quota.check_values(&parts)?;
+    let changes = parts
+      .iter()
+      .map(|part| (part.key.as_str(), Some(part.value.len() as u64)));
+    let usage = self.usage_after(changes)?;
+    if let (Some(before), Some(after)) = (&self.usage, &usage) {
+      self.quota.check_usage(before, after)?;
+    }
+    self.usage = usage;
+    self.content_hash = self
+      .content_hash
+      .after_write(|keys| self.get(keys), content::set_values(&parts))?;
+    let changes = self.watchers.is_watched().then(|| watch::set_changes(&parts));
+    self.bump_modifications_number();
     for part in parts {
-      self.current.insert(part.key, part.value);
+      self.current.insert(part.key, Some(part.value));
     }
+    self.watchers.send(self.etag(), changes);
     Ok(())
   }
 
-  fn get_checkpoints(&self) -> Result<Vec<Checkpoint>> {
+  fn delete<Key: AsRef<str>>(&mut self, keys: &[Key]) -> Result<()> {
+    keys.iter().try_for_each(|key| validate_key(key.as_ref()))?;
+    self.usage = self.usage_after(keys.iter().map(|key| (key.as_ref(), None)))?;
+    self.content_hash = self
+      .content_hash
+      .after_write(|keys| self.get(keys), keys.iter().map(|key| (key.as_ref(), None)))?;
+    let changes = self.watchers.is_watched().then(|| watch::delete_changes(keys));
+    self.bump_modifications_number();
+    for key in keys {
+      self.current.insert(key.as_ref().to_owned(), None);
+    }
+    self.watchers.send(self.etag(), changes);
+    Ok(())
+  }
+
+  fn get_at<Key: AsRef<str>>(&mut self, checkpoint_id: &str, keys: &[Key]) -> Result<Vec<KeyValue>> {
+    let layers = &self.checkpoints[..=self.checkpoint_index(checkpoint_id)?];
     let mut result = Vec::new();
-    for checkpoint in &self.checkpoints {
-      result.push(Checkpoint {
-        id: checkpoint.id.clone(),
-        payload: checkpoint.payload.clone(),
-      });
+    for key in keys {
+      let key = key.as_ref();
+      let change = layers.iter().rev().find_map(|checkpoint| checkpoint.changes.get(key));
+      if let Some(Some(value)) = change {
+        result.push(KeyValue {
+          key: key.to_owned(),
+          value: value.clone(),
+        });
+      }
     }
     Ok(result)
   }
 
-  // Simply clone the last checkpoint entirely and apply new changes
-  fn create_checkpoint(&mut self, payload: &str) -> Result<String> {
-    self.modifications_number += 1;
+  fn scan_at(
+    &mut self,
+    checkpoint_id: Option<&str>,
+    range: &KeyRange,
+    limit: usize,
+  ) -> Result<Vec<KeyValue>> {
+    match checkpoint_id {
+      Some(checkpoint_id) => {
+        let layers = &self.checkpoints[..=self.checkpoint_index(checkpoint_id)?];
+        let layers = layers.iter().rev().map(|checkpoint| &checkpoint.changes);
+        Ok(scan_layers(layers, range, limit))
+      }
+      None => self.scan(range, limit),
+    }
+  }
 
-    let mut values = match self.checkpoints.last() {
-      Some(last_checkpoint) => last_checkpoint.values.clone(),
-      None => KVMap::default(),
-    };
+  fn get_checkpoints(&self) -> Result<Vec<Checkpoint>> {
+    let result = self
+      .checkpoints
+      .iter()
+      .map(|checkpoint| checkpoint.checkpoint.clone())
+      .collect();
+    Ok(result)
+  }
+
+  // The checkpoint keeps only the changes made since the previous one
+  fn create_checkpoint(&mut self, payload: &str) -> Result<CreatedCheckpoint> {
+    let now = chrono::Utc::now();
+    let (new_id, evicted) = self.next_checkpoint(now)?;
+    self.bump_modifications_number();
 
-    for (key, value) in self.current.drain() {
-      values.insert(key, value);
+    let mut changes = std::mem::take(&mut self.current);
+    if self.checkpoints.is_empty() {
+      changes.retain(|_, change| change.is_some());
     }
 
-    let new_id = self.modifications_number.to_string();
     self.checkpoints.push(AppCheckpoint {
-      id: new_id.clone(),
-      payload: payload.to_owned(),
-      values,
+      checkpoint: Checkpoint {
+        id: new_id.clone(),
+        payload: payload.to_owned(),
+        created_at: Some(now),
+        pinned: false,
+        key_count: Some(self.content_hash.keys),
+        // Nothing is stored on disk
+        size: None,
+        modifications_number: Some(self.modifications_number),
+        content_hash: Some(self.content_hash.hex()),
+        usage: self.usage,
+      },
+      changes,
+      content_hash: self.content_hash,
     });
-    self.current.clear();
-    Ok(new_id)
+    self.drop_checkpoints(|_, checkpoint| evicted.contains(&checkpoint.id));
+    Ok(CreatedCheckpoint {
+      id: new_id,
+      evicted,
+    })
+  }
+
+  // Nothing is written if `set` fails, and the checkpoint quota is checked before it
+  fn commit(&mut self, parts: Vec<KeyValue>, payload: &str) -> Result<Committed> {
+    self.next_checkpoint(chrono::Utc::now())?;
+    self.set(parts)?;
+    Ok(Committed::Applied(Some(self.create_checkpoint(payload)?)))
+  }
+
+  // The parts are read into memory, where they're kept anyway, and set at once
+  fn bulk_set(&mut self, mut spool: Spool, checkpoint_payload: Option<&str>) -> Result<Committed> {
+    if checkpoint_payload.is_some() {
+      self.next_checkpoint(chrono::Utc::now())?;
+    }
+    let chunks: Vec<Vec<KeyValue>> = spool
+      .chunks(CHUNK_KEYS, CHUNK_BYTES)?
+      .collect::<std::io::Result<_>>()?;
+    self.set(chunks.into_iter().flatten().collect())?;
+    let checkpoint = checkpoint_payload
+      .map(|payload| self.create_checkpoint(payload))
+      .transpose()?;
+    Ok(Committed::Applied(checkpoint))
   }
 
   fn revert(&mut self, id: &str) -> Result<()> {
@@ -145,17 +454,20 @@ impl AppStateManager for InMemoryAppStateManager {
       .checkpoints
       .iter()
       .enumerate()
-      .find(|(_i, checkpoint)| checkpoint.id == id)
+      .find(|(_i, checkpoint)| checkpoint.checkpoint.id == id)
       .map(|(i, _checkpoint)| i);
     if let Some(index) = index {
-      self.modifications_number += 1;
+      self.bump_modifications_number();
       info!(
         "Dropping {} latest checkpoints to end up at {}",
         self.checkpoints.len() - index - 1,
         id
       );
       self.current.clear();
+      self.usage = self.checkpoints[index].checkpoint.usage;
+      self.content_hash = self.checkpoints[index].content_hash;
       self.checkpoints.truncate(index + 1);
+      self.watchers.send_resync(self.etag());
     } else {
       return Err(Error::NotFound(format!(
         "Checkpoint with id {} does not exist",
@@ -170,12 +482,15 @@ impl AppStateManager for InMemoryAppStateManager {
       .checkpoints
       .iter()
       .enumerate()
-      .find(|(_i, checkpoint)| checkpoint.id == until_checkpoint)
+      .find(|(_i, checkpoint)| checkpoint.checkpoint.id == until_checkpoint)
       .map(|(i, _checkpoint)| i);
     if let Some(index) = index {
-      self.modifications_number += 1;
-      let removed = self.checkpoints.drain(..index);
-      info!("Cleaned up {} checkpoints", removed.len());
+      let checkpoints = &self.checkpoints[..index];
+      if let Some(pinned) = checkpoints.iter().find(|cp| cp.checkpoint.pinned) {
+        return Err(pinned_checkpoint_error(&pinned.checkpoint));
+      }
+      self.bump_modifications_number();
+      self.drop_checkpoints(|i, _| i < index);
     } else {
       return Err(Error::NotFound(format!(
         "Checkpoint with id {} does not exist",
@@ -185,12 +500,56 @@ impl AppStateManager for InMemoryAppStateManager {
     Ok(())
   }
 
+  fn set_pinned(&mut self, id: &str, pinned: bool) -> Result<()> {
+    let index = self.checkpoint_index(id)?;
+    self.bump_modifications_number();
+    self.checkpoints[index].checkpoint.pinned = pinned;
+    Ok(())
+  }
+
   fn reset(&mut self) -> Result<()> {
+    // HEAD falls back to the latest checkpoint
+    self.bump_modifications_number();
     self.current.clear();
+    self.usage = match self.checkpoints.last() {
+      Some(checkpoint) => checkpoint.checkpoint.usage,
+      None => Some(Usage::default()),
+    };
+    self.content_hash = self
+      .checkpoints
+      .last()
+      .map(|checkpoint| checkpoint.content_hash)
+      .unwrap_or_default();
+    self.watchers.send_resync(self.etag());
     Ok(())
   }
 
-  fn modifications_number(&self) -> u32 {
+  fn watch(&self) -> ChangeReceiver {
+    self.watchers.subscribe()
+  }
+
+  fn quota(&self) -> Quota {
+    self.quota
+  }
+
+  fn info(&self) -> Result<AppInfo> {
+    let checkpoints = self.get_checkpoints()?;
+    Ok(AppInfo {
+      backend: None,
+      key_count: Some(self.content_hash.keys),
+      key_count_estimated: false,
+      disk_usage: None,
+      checkpoint_count: checkpoints.len(),
+      latest_checkpoint: checkpoints.last().cloned(),
+      last_write_at: self.last_write_at,
+    })
+  }
+
+  fn instance_id(&self) -> &str {
+    &self.instance_id
+  }
+
+  fn modifications_number(&self) -> u64 {
     self.modifications_number
   }
 
@@ -199,6 +558,6 @@ impl AppStateManager for InMemoryAppStateManager {
     _storage: &impl FileStorage,
     _prefix: &std::path::Path,
   ) -> Result<()> {
-    unimplemented!();
+    Err(snapshots_unsupported())
   }
 }
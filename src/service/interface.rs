@@ -1,52 +1,229 @@
+use super::diff::{self, KeyDiff};
+use super::quota::{Quota, Usage};
+use super::retention::Retention;
+use super::watch::ChangeReceiver;
 use crate::file_storage::interface::FileStorage;
-use crate::types::{KeyValue, Result};
+use crate::storage::any::Backend;
+use crate::types::{AppId, Error, KeyRange, KeyValue, Result};
+use crate::utils::spool::Spool;
 use async_trait::async_trait;
 
 #[async_trait]
 pub trait StateManager: Sync + Send {
   type AppStateManager: AppStateManager;
 
-  fn init_app(&self, id: &str) -> Result<()>;
+  fn init_app(&self, id: &AppId, options: &AppOptions) -> Result<()>;
 
   // We can't just return &mut AppStateManager because it would reference a local-scope RAII guard
   fn with_app<Out>(
     &self,
-    id: &str,
+    id: &AppId,
     f: impl FnOnce(&mut Self::AppStateManager) -> Out,
   ) -> Result<Out>;
 
+  // Same as `with_app` for modifications which replace HEAD. A pending commit, see
+  // `Committed::Pending`, isn't finished first, so that one which can't be applied is discarded
+  fn with_app_replacing_head<Out>(
+    &self,
+    id: &AppId,
+    f: impl FnOnce(&mut Self::AppStateManager) -> Out,
+  ) -> Result<Out> {
+    self.with_app(id, f)
+  }
+
   // TODO: find a way to do the same as above for async functions
   async fn store_snapshot(
     &self,
-    app_id: &str,
+    app_id: &AppId,
     storage: &impl FileStorage,
     prefix: &std::path::Path,
   ) -> Result<()>;
 
-  fn drop_app(&self, id: &str) -> Result<()>;
+  async fn snapshot_info(
+    &self,
+    storage: &impl FileStorage,
+    prefix: &std::path::Path,
+  ) -> Result<SnapshotInfo>;
+
+  // Creates a new app from a snapshot uploaded by `store_snapshot`
+  async fn restore_snapshot(
+    &self,
+    app_id: &AppId,
+    storage: &impl FileStorage,
+    prefix: &std::path::Path,
+  ) -> Result<()>;
+
+  fn drop_app(&self, id: &AppId) -> Result<()>;
+
+  // Ids of all the apps, including the ones which weren't used since the start, ordered by id
+  fn list_apps(&self) -> Result<Vec<AppId>>;
+
+  // Creates a new app with HEAD and the only checkpoint equal to the checkpoint of the source app
+  fn fork_app(&self, source_id: &AppId, checkpoint_id: &str, id: &AppId) -> Result<()>;
 }
 
 #[async_trait]
 pub trait AppStateManager: Sync + Send {
   fn get<Key: AsRef<str>>(&self, keys: &[Key]) -> Result<Vec<KeyValue>>;
+  fn scan(&self, range: &KeyRange, limit: usize) -> Result<Vec<KeyValue>>;
+  // Fails with `ResourceExhausted` if the app would exceed its quota
   fn set(&mut self, parts: Vec<KeyValue>) -> Result<()>;
+  fn delete<Key: AsRef<str>>(&mut self, keys: &[Key]) -> Result<()>;
+  // Reads the values the keys had when the checkpoint was created
+  fn get_at<Key: AsRef<str>>(&mut self, checkpoint_id: &str, keys: &[Key]) -> Result<Vec<KeyValue>>;
+  // Like `scan`, but reads the checkpoint if it's set
+  fn scan_at(
+    &mut self,
+    checkpoint_id: Option<&str>,
+    range: &KeyRange,
+    limit: usize,
+  ) -> Result<Vec<KeyValue>>;
   fn get_checkpoints(&self) -> Result<Vec<Checkpoint>>;
-  fn create_checkpoint(&mut self, payload: &str) -> Result<String>;
+  // Fails with `ResourceExhausted` if the app would have more checkpoints than its quota
+  // allows once the evicted ones are removed
+  fn create_checkpoint(&mut self, payload: &str) -> Result<CreatedCheckpoint>;
+  // Sets the parts and creates a checkpoint, so that both or none of them are applied.
+  // The quota is checked for both before anything is written
+  fn commit(&mut self, parts: Vec<KeyValue>, payload: &str) -> Result<Committed>;
+  // Sets the spooled parts as a single modification, optionally followed by a checkpoint.
+  // All the parts are checked first, so nothing is written if any of them is rejected
+  fn bulk_set(&mut self, spool: Spool, checkpoint_payload: Option<&str>) -> Result<Committed>;
+  // Discards a pending commit, see `Committed::Pending`
   fn revert(&mut self, id: &str) -> Result<()>;
+  // Fails if any of the removed checkpoints is pinned
   fn cleanup(&mut self, until_checkpoint: &str) -> Result<()>;
+  // Pinned checkpoints are never evicted by the retention policy
+  fn set_pinned(&mut self, id: &str, pinned: bool) -> Result<()>;
+  // Discards a pending commit, see `Committed::Pending`
   fn reset(&mut self) -> Result<()>;
 
+  fn info(&self) -> Result<AppInfo>;
+
+  // The server's default quota with the overrides of the app
+  fn quota(&self) -> Quota;
+
+  // Receives the keys changed by every following modification, and is closed once the app
+  // is dropped. Revert and reset send the values they restore
+  fn watch(&self) -> ChangeReceiver;
+
   async fn store_snapshot(
     &self,
     storage: &impl FileStorage,
     prefix: &std::path::Path,
   ) -> Result<()>;
 
-  fn modifications_number(&self) -> u32;
+  // Identifies this incarnation of the app, so that its modification numbers aren't confused
+  // with the ones of an app that was dropped and created again or restored from a snapshot
+  fn instance_id(&self) -> &str;
+
+  fn modifications_number(&self) -> u64;
+
+  // Keys of the range which differ between the checkpoints, `None` stands for HEAD
+  fn diff(
+    &mut self,
+    from: Option<&str>,
+    to: Option<&str>,
+    range: &KeyRange,
+    limit: usize,
+  ) -> Result<Vec<KeyDiff>> {
+    let scan = |checkpoint_id: Option<&str>, range: &KeyRange, limit| {
+      self.scan_at(checkpoint_id, range, limit)
+    };
+    diff::diff(scan, from, to, range, limit)
+  }
+
+  // Changes after every modification and stays the same across restarts of persistent apps
+  fn etag(&self) -> String {
+    format!("{}-{}", self.instance_id(), self.modifications_number())
+  }
 }
 
 #[derive(Debug, Default, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub struct Checkpoint {
   pub id: String,
   pub payload: String,
+  // Missing in manifests of checkpoints created by older versions
+  #[serde(default)]
+  pub created_at: Option<chrono::DateTime<chrono::Utc>>,
+  #[serde(default)]
+  pub pinned: bool,
+  // Fields below are missing in manifests of checkpoints created by older versions
+  #[serde(default)]
+  pub key_count: Option<u64>,
+  // Size of the checkpoint files, which contain only the changed keys for delta checkpoints
+  #[serde(default)]
+  pub size: Option<u64>,
+  // Modification number of the app when the checkpoint was created, see `etag`
+  #[serde(default)]
+  pub modifications_number: Option<u64>,
+  // Hex hash of the checkpoint contents, see `content::ContentHash`
+  #[serde(default)]
+  pub content_hash: Option<String>,
+  // Usage of the app if it was known when the checkpoint was created, so that it isn't
+  // measured again after a revert
+  #[serde(default)]
+  pub usage: Option<Usage>,
+}
+
+// Error of a cleanup which would remove a pinned checkpoint
+pub fn pinned_checkpoint_error(checkpoint: &Checkpoint) -> Error {
+  Error::FailedPrecondition(format!(
+    "Checkpoint {} is pinned and can't be cleaned up",
+    checkpoint.id
+  ))
+}
+
+// Result of `create_checkpoint`
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CreatedCheckpoint {
+  pub id: String,
+  // Checkpoints removed by the retention policy of the app
+  pub evicted: Vec<String>,
+}
+
+// Result of a commit or a bulk set which passed the checks
+#[derive(Debug)]
+pub enum Committed {
+  // With the checkpoint, if one was requested
+  Applied(Option<CreatedCheckpoint>),
+  // The commit was saved, but writing it failed. It's finished before the next request to
+  // the app, or discarded by a revert or a reset if it can't be, so it must not be retried
+  Pending(Error),
+}
+
+// Options of a newly created app. They are ignored if the app already exists,
+// except for the retention policy and the quota, which replace the current ones
+#[derive(Debug, Default, Clone)]
+pub struct AppOptions {
+  // Storage backend, the manager's default is used if not set
+  pub backend: Option<Backend>,
+  // The manager's default is used if not set
+  pub retention: Option<Retention>,
+  // Overrides of the manager's default quota
+  pub quota: Option<Quota>,
+}
+
+// Summary of an app for administration
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AppInfo {
+  // `None` for in-memory apps
+  pub backend: Option<Backend>,
+  pub key_count: Option<u64>,
+  // Set if the backend only estimates the key count, see `KVStorage::key_count`
+  pub key_count_estimated: bool,
+  // Size of HEAD and the checkpoints in bytes, `None` for in-memory apps.
+  // Files shared by several checkpoints are counted for each of them
+  pub disk_usage: Option<u64>,
+  pub checkpoint_count: usize,
+  pub latest_checkpoint: Option<Checkpoint>,
+  // Time of the last modification, `None` if there were none since the app was created
+  pub last_write_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotInfo {
+  pub checkpoint: Checkpoint,
+  // Total size of the uploaded files in bytes
+  pub size: u64,
+  pub uploaded_at: chrono::DateTime<chrono::Utc>,
 }
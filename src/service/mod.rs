@@ -1,5 +1,10 @@
+pub mod content;
+pub mod diff;
 pub mod in_memory;
 pub mod interface;
 pub mod persistent;
+pub mod quota;
+pub mod retention;
+pub mod watch;
 #[cfg(test)]
 pub mod tests;
@@ -1,18 +1,63 @@
-use super::interface::{AppStateManager, Checkpoint, StateManager};
+use super::interface::{
+  pinned_checkpoint_error, AppInfo, AppOptions, AppStateManager, Checkpoint, Committed,
+  CreatedCheckpoint,
+  SnapshotInfo, StateManager,
+};
+use super::content::{self, ContentHash};
+use super::quota::{Quota, Usage, SPOOL_BUCKET_KEYS};
+use super::retention::Retention;
+use super::watch::{self, ChangeReceiver, Watchers};
 use crate::file_storage::interface::FileStorage;
-use crate::storage::interface::KVStorage;
-use crate::types::{Error, KeyValue, Result};
+use crate::storage::any::{AnyStorage, Backend};
+use crate::storage::interface::KeyCount;
+use crate::types::{validate_key, AppId, Error, KeyRange, KeyValue, Result};
+use crate::utils::fs::{dir_size, sync_dir, sync_tree, write_atomic};
+use crate::utils::random_string;
+use crate::utils::spool::{Chunks, Spool, CHUNK_BYTES, CHUNK_KEYS};
 use async_trait::async_trait;
 use dashmap::DashMap;
-use log::info;
+use log::{error, info, warn};
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
+use std::collections::{HashSet, VecDeque};
 use std::path::{Path, PathBuf};
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 struct AppManifest {
   checkpoints: Vec<Checkpoint>,
   version: Option<String>,
+  // Missing in manifests of apps created before backends could be chosen per app
+  #[serde(default)]
+  backend: Option<Backend>,
+  // Generated when the app is created, not included in snapshots
+  #[serde(default)]
+  instance_id: Option<String>,
+  // Bumped and saved before every modification, so an etag is never reused for another state
+  #[serde(default)]
+  modifications_number: u64,
+  #[serde(default)]
+  retention: Retention,
+  // Overrides of the server's default quota
+  #[serde(default)]
+  quota: Quota,
+  // Set while a commit or a bulk set is written, an interrupted one is finished from its
+  // saved parts
+  #[serde(default)]
+  pending_commit: bool,
+  // Saved with the modification number
+  #[serde(default)]
+  last_write_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+// Parts and payload of a commit, saved before it's written so that it can be finished
+#[derive(Debug, Serialize, Deserialize)]
+struct PendingCommit {
+  #[serde(default)]
+  parts: Vec<(String, Vec<u8>)>,
+  // Whether the parts of a bulk set follow in the spool file next to it
+  #[serde(default)]
+  spooled: bool,
+  // `None` if no checkpoint is created after the parts
+  payload: Option<String>,
 }
 
 impl Default for AppManifest {
@@ -20,38 +65,65 @@ impl Default for AppManifest {
     Self {
       checkpoints: Vec::new(),
       version: Some("1".to_owned()),
+      backend: None,
+      instance_id: None,
+      modifications_number: 0,
+      retention: Retention::default(),
+      quota: Quota::default(),
+      pending_commit: false,
+      last_write_at: None,
     }
   }
 }
 
-#[derive(Default, Debug)]
-pub struct PersistentStateManager<Storage: KVStorage> {
+pub struct PersistentStateManager {
   root: PathBuf,
-  apps: DashMap<String, PersistentAppStateManager<Storage>>,
+  // Backend of apps which don't specify it
+  default_backend: Backend,
+  // Quota of apps which don't override it
+  default_quota: Quota,
+  apps: DashMap<String, PersistentAppStateManager>,
 }
 
-#[derive(Default, Debug)]
-pub struct PersistentAppStateManager<Storage: KVStorage> {
+// Number of checkpoints kept open for `get_at` per app
+const OPEN_CHECKPOINTS: usize = 4;
+
+#[derive(Default)]
+pub struct PersistentAppStateManager {
   root: PathBuf,
   manifest: AppManifest,
-  storage: Option<Storage>,
-  modifications_number: u32,
+  storage: Option<AnyStorage>,
+  // Recently read checkpoints, the most recent one is the last
+  open_checkpoints: VecDeque<(String, AnyStorage)>,
+  default_quota: Quota,
+  // Measured on the first write if the quota limits it, unknown after HEAD is replaced
+  usage: Option<Usage>,
+  // Known after a checkpoint is created or restored and kept up to date by writes
+  content_hash: Option<ContentHash>,
+  watchers: Watchers,
 }
 
-impl<Storage: KVStorage> PersistentStateManager<Storage> {
-  pub fn new(root: impl Into<PathBuf>) -> Self {
+impl PersistentStateManager {
+  pub fn new(root: impl Into<PathBuf>, default_backend: Backend) -> Self {
     Self {
       root: root.into(),
+      default_backend,
+      default_quota: Quota::default(),
       apps: Default::default(),
     }
   }
 
+  pub fn with_default_quota(mut self, quota: Quota) -> Self {
+    self.default_quota = quota;
+    self
+  }
+
   fn app_path(&self, app_id: impl AsRef<Path>) -> PathBuf {
     self.root.join(app_id)
   }
 }
 
-impl<Storage: KVStorage> PersistentAppStateManager<Storage> {
+impl PersistentAppStateManager {
   fn checkpoints_dir(root: impl AsRef<Path>) -> PathBuf {
     root.as_ref().join("checkpoints")
   }
@@ -60,6 +132,21 @@ impl<Storage: KVStorage> PersistentAppStateManager<Storage> {
     root.as_ref().join("HEAD")
   }
 
+  // HEAD being built from a checkpoint, replaces HEAD once complete
+  fn new_head_path(root: impl AsRef<Path>) -> PathBuf {
+    root.as_ref().join("HEAD.new")
+  }
+
+  // Written once HEAD.new is complete and synced, removed once it replaces HEAD
+  fn new_head_ready_path(root: impl AsRef<Path>) -> PathBuf {
+    root.as_ref().join("HEAD.new.ready")
+  }
+
+  // Replaced HEAD, removed once the replacement is durable
+  fn old_head_path(root: impl AsRef<Path>) -> PathBuf {
+    root.as_ref().join("HEAD.old")
+  }
+
   fn checkpoint_path(root: impl AsRef<Path>, checkpoint: impl AsRef<Path>) -> PathBuf {
     Self::checkpoints_dir(root).join(checkpoint)
   }
@@ -68,12 +155,50 @@ impl<Storage: KVStorage> PersistentAppStateManager<Storage> {
     root.as_ref().join("manifest.json")
   }
 
-  fn new(root: PathBuf) -> Result<Self> {
-    std::fs::create_dir_all(Self::checkpoints_dir(&root))?;
-    Self::load(root)
+  fn pending_commit_path(root: impl AsRef<Path>) -> PathBuf {
+    root.as_ref().join("commit.json")
+  }
+
+  fn pending_spool_path(root: impl AsRef<Path>) -> PathBuf {
+    root.as_ref().join("commit.spool")
   }
 
-  fn load(root: PathBuf) -> Result<Self> {
+  // Existing apps are loaded with the backend they were created with
+  fn new(root: PathBuf, backend: Backend, default_quota: Quota) -> Result<Self> {
+    if !root.exists() {
+      Self::create(&root, backend)?;
+    }
+    Self::load(root, default_quota)
+  }
+
+  // The app is prepared in a temporary directory, so that every app without a backend
+  // in its manifest was created before backends could be chosen
+  fn create(root: &Path, backend: Backend) -> Result<()> {
+    let name = root.file_name().unwrap_or_default().to_string_lossy();
+    let tmp_path = root.with_file_name(format!(".init-{}-{}", name, random_string(6)));
+    let manifest = AppManifest {
+      backend: Some(backend),
+      instance_id: Some(random_string(8)),
+      ..Default::default()
+    };
+    let contents = serde_json::to_string(&manifest).map_err(std::io::Error::from)?;
+    let result = std::fs::create_dir_all(Self::checkpoints_dir(&tmp_path))
+      .and_then(|()| write_atomic(Self::manifest_path(&tmp_path), contents))
+      .and_then(|()| sync_tree(&tmp_path))
+      .and_then(|()| std::fs::rename(&tmp_path, root));
+    if let Err(err) = result {
+      let _ = std::fs::remove_dir_all(&tmp_path);
+      return Err(err.into());
+    }
+    match root.parent() {
+      Some(parent) if !parent.as_os_str().is_empty() => sync_dir(parent)?,
+      _ => sync_dir(".")?,
+    }
+    Ok(())
+  }
+
+  // Apps without a backend in the manifest were created with the filesystem one
+  fn load(root: PathBuf, default_quota: Quota) -> Result<Self> {
     if !root.is_dir() {
       return Err(Error::NotFound("Application does not exist".to_owned()));
     }
@@ -83,22 +208,59 @@ impl<Storage: KVStorage> PersistentAppStateManager<Storage> {
       root: root.clone(),
       manifest,
       storage: None,
-      modifications_number: 0,
+      open_checkpoints: VecDeque::new(),
+      default_quota,
+      usage: None,
+      content_hash: None,
+      watchers: Watchers::default(),
     };
+    if result.manifest.backend.is_none() || result.manifest.instance_id.is_none() {
+      result.manifest.backend.get_or_insert(Backend::Filesystem);
+      result.manifest.instance_id.get_or_insert_with(|| random_string(8));
+      result.save_manifest()?;
+    }
 
-    result.storage = Some(Storage::open(Self::head_path(&root))?);
+    result.recover_head()?;
+    result.storage = Some(AnyStorage::open(result.backend(), Self::head_path(&root))?);
+    if result.storage().is_legacy() {
+      result.migrate_head()?;
+    }
     result.restore_consistency()?;
+    // HEAD is still the last checkpoint if nothing was modified since it was created
+    if let Some(checkpoint) = result.manifest.checkpoints.last() {
+      if checkpoint.modifications_number == Some(result.manifest.modifications_number) {
+        result.usage = checkpoint.usage;
+        result.content_hash = ContentHash::of_checkpoint(checkpoint);
+      }
+    }
+    // A pending commit is finished by the first request, unless it's discarded
+    if !result.manifest.pending_commit {
+      // Left by a crash before the commit started or after its checkpoint was saved
+      result.remove_pending_commit()?;
+    }
     Ok(result)
   }
 
-  fn storage(&self) -> &Storage {
+  fn backend(&self) -> Backend {
+    self.manifest.backend.expect("Backend is set on load")
+  }
+
+  fn storage(&self) -> &AnyStorage {
     self.storage.as_ref().unwrap()
   }
 
-  fn storage_mut(&mut self) -> &mut Storage {
+  fn storage_mut(&mut self) -> &mut AnyStorage {
     self.storage.as_mut().unwrap()
   }
 
+  // Must be called before a modification: a failed modification only makes the etag stale,
+  // while a modification without a saved bump would reuse the etag after a restart
+  fn bump_modifications_number(&mut self) -> Result<()> {
+    self.manifest.modifications_number += 1;
+    self.manifest.last_write_at = Some(chrono::Utc::now());
+    self.save_manifest()
+  }
+
   fn load_manifest(path: impl AsRef<Path>) -> Result<AppManifest> {
     if let Ok(contents) = std::fs::read_to_string(path) {
       serde_json::from_str(&contents).map_err(|err| std::io::Error::from(err).into())
@@ -107,10 +269,281 @@ impl<Storage: KVStorage> PersistentAppStateManager<Storage> {
     }
   }
 
+  fn set_retention(&mut self, retention: Retention) -> Result<()> {
+    if self.manifest.retention != retention {
+      info!("Changing retention of {} to {:?}", self.root.display(), retention);
+      self.manifest.retention = retention;
+      self.save_manifest()?;
+    }
+    Ok(())
+  }
+
+  fn set_quota(&mut self, quota: Quota) -> Result<()> {
+    if self.manifest.quota != quota {
+      info!("Changing quota of {} to {:?}", self.root.display(), quota);
+      self.manifest.quota = quota;
+      self.save_manifest()?;
+    }
+    Ok(())
+  }
+
+  // Usage after the changes, or `None` if the quota doesn't limit it
+  fn usage_after<'a>(
+    &mut self,
+    changes: impl IntoIterator<Item = (&'a str, Option<u64>)>,
+  ) -> Result<Option<Usage>> {
+    if !self.quota().limits_usage() {
+      return Ok(None);
+    }
+    let usage = match self.usage {
+      Some(usage) => usage,
+      None => {
+        let usage = Usage::measure(self)?;
+        self.usage = Some(usage);
+        usage
+      }
+    };
+    usage.after_write(self, changes).map(Some)
+  }
+
+  // Hash after the changes, or `None` if `hash` isn't known
+  fn hash_after<'a>(
+    &self,
+    hash: Option<ContentHash>,
+    changes: impl IntoIterator<Item = (&'a str, Option<&'a [u8]>)>,
+  ) -> Result<Option<ContentHash>> {
+    hash
+      .map(|hash| hash.after_write(|keys| self.get(keys), changes))
+      .transpose()
+  }
+
+  // Validates the parts and checks them against the quota, returns the usage after writing them
+  fn check_set(&mut self, parts: &[KeyValue]) -> Result<Option<Usage>> {
+    parts.iter().try_for_each(|part| validate_key(&part.key))?;
+    let quota = self.quota();
+    quota.check_values(parts)?;
+    let changes = parts
+      .iter()
+      .map(|part| (part.key.as_str(), Some(part.value.len() as u64)));
+    let usage = self.usage_after(changes)?;
+    if let (Some(before), Some(after)) = (&self.usage, &usage) {
+      quota.check_usage(before, after)?;
+    }
+    Ok(usage)
+  }
+
+  // Checks all the spooled parts like `check_set`, without reading all of them into memory
+  fn check_spool(&mut self, spool: &mut Spool) -> Result<Option<Usage>> {
+    let quota = self.quota();
+    for chunk in spool.chunks(CHUNK_KEYS, CHUNK_BYTES)? {
+      let chunk = chunk?;
+      chunk.iter().try_for_each(|part| validate_key(&part.key))?;
+      quota.check_values(&chunk)?;
+    }
+    let before = match self.usage_after([])? {
+      Some(before) => before,
+      None => return Ok(None),
+    };
+    let after = before.after_spool(self, spool, SPOOL_BUCKET_KEYS)?;
+    quota.check_usage(&before, &after)?;
+    Ok(Some(after))
+  }
+
+  // Id of the next checkpoint and the ones evicted once it's created `now`.
+  // Fails if the quota doesn't allow one more checkpoint
+  fn next_checkpoint(&self, now: chrono::DateTime<chrono::Utc>) -> Result<(String, Vec<String>)> {
+    let new_id = self
+      .get_checkpoint_ids()
+      .last()
+      .map_or(0, |id| id + 1)
+      .to_string();
+    let checkpoints = &self.manifest.checkpoints;
+    let evicted = self.manifest.retention.evicted(checkpoints, &new_id, now);
+    self
+      .quota()
+      .check_checkpoints(checkpoints.len() + 1 - evicted.len())?;
+    Ok((new_id, evicted))
+  }
+
+  // Saves the commit and marks it pending, so that it's finished even if writing it fails.
+  // Nothing is pending if saving it fails
+  fn start_commit(&mut self, pending: &PendingCommit) -> Result<()> {
+    let contents = serde_json::to_vec(pending).map_err(std::io::Error::from)?;
+    let result = write_atomic(Self::pending_commit_path(&self.root), contents)
+      .map_err(Error::from)
+      .and_then(|()| {
+        self.manifest.pending_commit = true;
+        self.bump_modifications_number()
+      });
+    if result.is_err() {
+      // A saved manifest which marks the commit pending is ignored without its parts
+      self.manifest.pending_commit = false;
+      let _ = self.remove_pending_commit();
+    }
+    result
+  }
+
+  // Starts and applies the commit, which is reported pending if it's saved but not written
+  fn run_commit(&mut self, pending: PendingCommit, usage: Option<Usage>) -> Result<Committed> {
+    self.start_commit(&pending)?;
+    match self.apply_commit(pending, usage) {
+      Ok(checkpoint) => Ok(Committed::Applied(checkpoint)),
+      Err(err) => {
+        error!("Failed to apply commit of {}, it stays pending: {}", self.root.display(), err);
+        Ok(Committed::Pending(err))
+      }
+    }
+  }
+
+  // Writes the parts of a pending commit and creates its checkpoint, then clears `pending_commit`.
+  // Writing a part again is harmless, so an interrupted commit is finished by applying it again.
+  // `usage` is the usage once the parts are written, if it's known
+  fn apply_commit(
+    &mut self,
+    pending: PendingCommit,
+    usage: Option<Usage>,
+  ) -> Result<Option<CreatedCheckpoint>> {
+    let spool_path = Self::pending_spool_path(&self.root);
+    let parts = pending
+      .parts
+      .into_iter()
+      .map(|(key, value)| KeyValue { key, value })
+      .collect();
+    let spooled = match pending.spooled {
+      true => Some(Chunks::open(&spool_path, CHUNK_KEYS, CHUNK_BYTES)?),
+      false => None,
+    };
+    let mut changes = self.watchers.is_watched().then(Vec::new);
+    // The usage and the hash are unknown if the write fails halfway
+    self.usage = None;
+    let mut hash = self.content_hash.take();
+    for chunk in std::iter::once(Ok(parts)).chain(spooled.into_iter().flatten()) {
+      let chunk = chunk?;
+      if let Some(changes) = &mut changes {
+        // Past the limit the watchers get a resync event, so the rest isn't kept
+        if changes.len() <= watch::MAX_EVENT_CHANGES {
+          changes.extend(watch::set_changes(&chunk));
+        }
+      }
+      // Earlier chunks are already written, so a key repeated in them is replaced
+      hash = self.hash_after(hash, content::set_values(&chunk))?;
+      self.storage_mut().write(chunk)?;
+    }
+    self.usage = usage;
+    self.content_hash = hash;
+    let checkpoint = match pending.payload {
+      Some(payload) => Some(self.create_checkpoint(&payload)?),
+      None => {
+        self.manifest.pending_commit = false;
+        self.save_manifest()?;
+        None
+      }
+    };
+    self.remove_pending_commit()?;
+    self.watchers.send(self.etag(), changes);
+    Ok(checkpoint)
+  }
+
+  // Finishes a commit interrupted by a crash or a failed write, before any other modification
+  fn finish_commit(&mut self) -> Result<()> {
+    info!("Finishing incomplete commit of {}", self.root.display());
+    let contents = match std::fs::read_to_string(Self::pending_commit_path(&self.root)) {
+      Ok(contents) => contents,
+      // Removed because saving the manifest failed, and the commit was rejected
+      Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+        self.manifest.pending_commit = false;
+        return self.save_manifest();
+      }
+      Err(err) => return Err(err.into()),
+    };
+    let pending = serde_json::from_str(&contents).map_err(std::io::Error::from)?;
+    self.apply_commit(pending, None).map_err(|err| {
+      Error::FailedPrecondition(format!(
+        "Pending commit can't be applied, revert or reset the app to discard it: {}",
+        err
+      ))
+    })?;
+    Ok(())
+  }
+
+  // The flag is saved with the next bump, before HEAD is replaced, so that a crash can't
+  // apply the commit to the new HEAD
+  fn discard_commit(&mut self) {
+    if self.manifest.pending_commit {
+      warn!("Discarding pending commit of {}", self.root.display());
+      self.manifest.pending_commit = false;
+    }
+  }
+
+  fn remove_pending_commit(&self) -> Result<()> {
+    for path in [Self::pending_commit_path(&self.root), Self::pending_spool_path(&self.root)] {
+      if path.exists() {
+        std::fs::remove_file(path)?;
+      }
+    }
+    Ok(())
+  }
+
   fn save_manifest(&self) -> Result<()> {
     let contents = serde_json::to_string(&self.manifest).map_err(std::io::Error::from)?;
-    let path = Self::manifest_path(&self.root);
-    std::fs::write(path, contents)?;
+    write_atomic(Self::manifest_path(&self.root), contents)?;
+    Ok(())
+  }
+
+  // Finishes or rolls back HEAD replacement interrupted by a crash.
+  // The replacement is committed once the old HEAD is renamed.
+  fn recover_head(&self) -> Result<()> {
+    let head_path = Self::head_path(&self.root);
+    let new_head_path = Self::new_head_path(&self.root);
+    let ready_path = Self::new_head_ready_path(&self.root);
+    let old_head_path = Self::old_head_path(&self.root);
+    if new_head_path.exists() {
+      // HEAD may be missing because it was emptied rather than renamed, so only the marker
+      // tells that the new HEAD is complete. Replacements started by older versions left
+      // no marker, but renamed HEAD only once the new one was complete
+      let is_complete = ready_path.exists() || old_head_path.exists();
+      if !head_path.exists() && is_complete {
+        info!("Moving {} into place", new_head_path.display());
+        std::fs::rename(&new_head_path, &head_path)?;
+        sync_dir(&self.root)?;
+      } else {
+        info!("Removing incomplete {}", new_head_path.display());
+        AnyStorage::destroy(self.backend(), &new_head_path)?;
+      }
+    }
+    if ready_path.exists() {
+      std::fs::remove_file(&ready_path)?;
+    }
+    if old_head_path.exists() {
+      info!("Removing {}", old_head_path.display());
+      AnyStorage::destroy(self.backend(), &old_head_path)?;
+    }
+    Ok(())
+  }
+
+  // Atomically replaces HEAD with `new_head_path` if it's set, or with an empty storage
+  fn replace_head(&mut self, new_head_path: Option<&Path>) -> Result<()> {
+    let head_path = Self::head_path(&self.root);
+    let old_head_path = Self::old_head_path(&self.root);
+
+    self.storage = None; // closes connection to current db
+    self.usage = None;
+    self.content_hash = None;
+    if head_path.exists() {
+      std::fs::rename(&head_path, &old_head_path)?;
+    }
+    if let Some(new_head_path) = new_head_path {
+      std::fs::rename(new_head_path, &head_path)?;
+    }
+    sync_dir(&self.root)?;
+    let ready_path = Self::new_head_ready_path(&self.root);
+    if ready_path.exists() {
+      std::fs::remove_file(&ready_path)?;
+    }
+    if old_head_path.exists() {
+      AnyStorage::destroy(self.backend(), &old_head_path)?;
+    }
+    self.storage = Some(AnyStorage::open(self.backend(), head_path)?);
     Ok(())
   }
 
@@ -186,151 +619,559 @@ impl<Storage: KVStorage> PersistentAppStateManager<Storage> {
     std::fs::remove_dir_all(Self::checkpoint_path(&self.root, id)).map_err(From::from)
   }
 
-  fn remove_checkpoints(&mut self, slice: impl std::ops::RangeBounds<usize>) -> Result<()> {
-    let to_remove: Vec<_> = self.manifest.checkpoints.drain(slice).collect();
+  // Checkpoints may store only the changes against the previous one, so the ones following
+  // the removed checkpoints are rebased onto the closest remaining predecessor first
+  fn drop_checkpoints(&mut self, remove: impl Fn(usize, &Checkpoint) -> bool) -> Result<()> {
+    let mut kept = Vec::new();
+    let mut to_remove = Vec::new();
+    let mut parent_removed = false;
+    for (i, checkpoint) in self.manifest.checkpoints.iter().enumerate() {
+      if remove(i, checkpoint) {
+        to_remove.push(checkpoint.id.clone());
+        parent_removed = true;
+        continue;
+      }
+      if parent_removed {
+        let new_parent = kept
+          .last()
+          .map(|parent: &Checkpoint| Self::checkpoint_path(&self.root, &parent.id));
+        AnyStorage::rebase_checkpoint(
+          self.backend(),
+          &Self::checkpoint_path(&self.root, &checkpoint.id),
+          new_parent.as_deref(),
+        )?;
+        parent_removed = false;
+      }
+      kept.push(checkpoint.clone());
+    }
+    if to_remove.is_empty() {
+      return Ok(());
+    }
+
     info!("Cleaning up {} checkpoints", to_remove.len());
+    // Ids of removed checkpoints may be reused later
+    self
+      .open_checkpoints
+      .retain(|(id, _storage)| !to_remove.contains(id));
+    self.manifest.checkpoints = kept;
     self.save_manifest()?;
     to_remove
       .into_iter()
-      .try_for_each(|checkpoint| self.remove_checkpoint(&checkpoint.id))?;
+      .try_for_each(|id| self.remove_checkpoint(&id))?;
     Ok(())
   }
 
-  // TODO: optimize for a generic case
   fn reset_head(&mut self, checkpoint_id: impl AsRef<Path>) -> Result<()> {
-    let head_path = Self::head_path(&self.root);
+    let new_head_path = Self::new_head_path(&self.root);
     let checkpoint_path = Self::checkpoint_path(&self.root, checkpoint_id);
 
-    self.storage = None; // closes connection to current db
-    Storage::destroy(&head_path)?;
-    let checkpoint_db = Storage::open(checkpoint_path)?;
-    checkpoint_db.save_copy(&head_path)?;
-    self.storage = Some(Storage::open(head_path)?);
+    if new_head_path.exists() {
+      AnyStorage::destroy(self.backend(), &new_head_path)?;
+    }
+    AnyStorage::clone_dir(self.backend(), &checkpoint_path, &new_head_path)?;
+    sync_tree(&new_head_path)?;
+    write_atomic(Self::new_head_ready_path(&self.root), "")?;
+    self.replace_head(Some(&new_head_path))?;
+    self.storage_mut().mark_checkpoint(&checkpoint_path)
+  }
+
+  // HEAD written by an older version can't be written, so it's replaced with a copy
+  // the same way a revert replaces it, and a crash leaves either of them
+  fn migrate_head(&mut self) -> Result<()> {
+    let new_head_path = Self::new_head_path(&self.root);
+    info!("Rewriting {} in the current format", Self::head_path(&self.root).display());
+    self.storage().save_copy(&new_head_path)?;
+    sync_tree(&new_head_path)?;
+    write_atomic(Self::new_head_ready_path(&self.root), "")?;
+    self.replace_head(Some(&new_head_path))
+  }
+
+  async fn download_snapshot_manifest(
+    storage: &impl FileStorage,
+    prefix: &Path,
+  ) -> Result<AppManifest> {
+    let contents = storage.download_buffer(&Self::manifest_path(prefix)).await?;
+    let manifest: AppManifest =
+      serde_json::from_slice(&contents).map_err(std::io::Error::from)?;
+    if manifest.checkpoints.is_empty() {
+      return Err(Error::NotFound(
+        "Snapshot manifest does not contain any checkpoints".to_owned(),
+      ));
+    }
+    Ok(manifest)
+  }
+
+  async fn snapshot_info(storage: &impl FileStorage, prefix: &Path) -> Result<SnapshotInfo> {
+    let files = storage.list(prefix).await?;
+    // The manifest is uploaded last, so a snapshot without it is incomplete
+    let manifest_file = files
+      .iter()
+      .find(|file| file.path == Self::manifest_path(""))
+      .ok_or_else(|| Error::NotFound(format!("Snapshot {} not found", prefix.display())))?;
+    let manifest = Self::download_snapshot_manifest(storage, prefix).await?;
+    Ok(SnapshotInfo {
+      checkpoint: manifest.checkpoints.last().unwrap().clone(),
+      size: files.iter().map(|file| file.size).sum(),
+      uploaded_at: manifest_file.last_modified,
+    })
+  }
+
+  // Downloads the snapshot checkpoint and makes a HEAD out of it
+  async fn restore_snapshot(
+    root: &Path,
+    storage: &impl FileStorage,
+    prefix: &Path,
+  ) -> Result<()> {
+    let mut manifest = Self::download_snapshot_manifest(storage, prefix).await?;
+    // Snapshots without a backend were uploaded by filesystem apps
+    let backend = *manifest.backend.get_or_insert(Backend::Filesystem);
+    // The restored app is a new instance even if it has the same id as the snapshotted one
+    manifest.instance_id = None;
+    manifest.modifications_number = 0;
+    let checkpoint = manifest.checkpoints.last().unwrap();
+
+    let checkpoint_path = Self::checkpoint_path(root, &checkpoint.id);
+    std::fs::create_dir_all(&checkpoint_path)?;
+    storage
+      .download_folder(&Self::checkpoint_path(prefix, &checkpoint.id), &checkpoint_path)
+      .await?;
+    AnyStorage::clone_dir(backend, &checkpoint_path, Self::head_path(root))?;
+
+    let contents = serde_json::to_string(&manifest).map_err(std::io::Error::from)?;
+    std::fs::write(Self::manifest_path(root), contents)?;
+    // The directory is renamed into place after this, so it must be complete by then
+    sync_tree(root)?;
     Ok(())
   }
 
-  fn clean_head(&mut self) -> Result<()> {
-    let head_path = Self::head_path(&self.root);
-    self.storage = None;
-    Storage::destroy(&head_path)?;
-    self.storage = Some(Storage::open(head_path)?);
+  // Opens the checkpoint for reading or takes it from the recently read ones
+  fn open_checkpoint(&mut self, checkpoint_id: &str) -> Result<&AnyStorage> {
+    self.find_checkpoint(checkpoint_id)?;
+    let index = self
+      .open_checkpoints
+      .iter()
+      .position(|(id, _storage)| id == checkpoint_id);
+    let entry = match index {
+      Some(index) => self.open_checkpoints.remove(index).unwrap(),
+      None => {
+        let path = Self::checkpoint_path(&self.root, checkpoint_id);
+        let storage = AnyStorage::open_read_only(self.backend(), path)?;
+        (checkpoint_id.to_owned(), storage)
+      }
+    };
+    if self.open_checkpoints.len() >= OPEN_CHECKPOINTS {
+      self.open_checkpoints.pop_front();
+    }
+    self.open_checkpoints.push_back(entry);
+    Ok(&self.open_checkpoints.back().unwrap().1)
+  }
+
+  // Creates an app at `root` with HEAD and the only checkpoint copied from the checkpoint
+  fn fork(&self, checkpoint_id: &str, root: &Path) -> Result<()> {
+    let index = self.find_checkpoint(checkpoint_id)?;
+    std::fs::create_dir_all(Self::checkpoints_dir(root))?;
+    let checkpoint_path = Self::checkpoint_path(root, checkpoint_id);
+    AnyStorage::clone_dir(
+      self.backend(),
+      Self::checkpoint_path(&self.root, checkpoint_id),
+      &checkpoint_path,
+    )?;
+    AnyStorage::clone_dir(self.backend(), &checkpoint_path, Self::head_path(root))?;
+
+    // The copy is complete even if the source checkpoint stores only changes
+    let checkpoint = Checkpoint {
+      pinned: false,
+      size: Some(dir_size(&checkpoint_path)?),
+      ..self.manifest.checkpoints[index].clone()
+    };
+    let manifest = AppManifest {
+      checkpoints: vec![checkpoint],
+      backend: self.manifest.backend,
+      retention: self.manifest.retention,
+      quota: self.manifest.quota,
+      ..Default::default()
+    };
+    let contents = serde_json::to_string(&manifest).map_err(std::io::Error::from)?;
+    std::fs::write(Self::manifest_path(root), contents)?;
+    // The directory is renamed into place after this, so it must be complete by then
+    sync_tree(root)?;
     Ok(())
   }
+
+  fn clean_head(&mut self) -> Result<()> {
+    self.replace_head(None)
+  }
 }
 
 #[async_trait]
-impl<Storage: KVStorage> StateManager for PersistentStateManager<Storage> {
-  type AppStateManager = PersistentAppStateManager<Storage>;
+impl StateManager for PersistentStateManager {
+  type AppStateManager = PersistentAppStateManager;
 
-  fn init_app(&self, id: &str) -> Result<()> {
-    self
+  fn init_app(&self, id: &AppId, options: &AppOptions) -> Result<()> {
+    let backend = options.backend.unwrap_or(self.default_backend);
+    let mut app = self
       .apps
-      .entry(id.to_owned())
-      .or_try_insert_with(|| PersistentAppStateManager::new(self.app_path(id)))?;
+      .entry(id.to_string())
+      .or_try_insert_with(|| {
+        PersistentAppStateManager::new(self.app_path(id), backend, self.default_quota)
+      })?;
+    if options.backend.is_some_and(|backend| backend != app.backend()) {
+      return Err(Error::FailedPrecondition(format!(
+        "App {} already exists with {:?} backend",
+        id,
+        app.backend()
+      )));
+    }
+    if let Some(retention) = options.retention {
+      app.set_retention(retention)?;
+    }
+    if let Some(quota) = options.quota {
+      app.set_quota(quota)?;
+    }
     Ok(())
   }
 
   fn with_app<Out>(
     &self,
-    id: &str,
+    id: &AppId,
     f: impl FnOnce(&mut Self::AppStateManager) -> Out,
   ) -> Result<Out> {
     let mut app = self
       .apps
-      .entry(id.to_owned())
-      .or_try_insert_with(|| PersistentAppStateManager::load(self.app_path(id)))?;
+      .entry(id.to_string())
+      .or_try_insert_with(|| PersistentAppStateManager::load(self.app_path(id), self.default_quota))?;
+    if app.manifest.pending_commit {
+      app.finish_commit()?;
+    }
+    Ok(f(&mut app))
+  }
+
+  fn with_app_replacing_head<Out>(
+    &self,
+    id: &AppId,
+    f: impl FnOnce(&mut Self::AppStateManager) -> Out,
+  ) -> Result<Out> {
+    let mut app = self
+      .apps
+      .entry(id.to_string())
+      .or_try_insert_with(|| PersistentAppStateManager::load(self.app_path(id), self.default_quota))?;
     Ok(f(&mut app))
   }
 
   async fn store_snapshot(
     &self,
-    app_id: &str,
+    app_id: &AppId,
     storage: &impl FileStorage,
     prefix: &std::path::Path,
   ) -> Result<()> {
     let app = self
       .apps
-      .entry(app_id.to_owned())
-      .or_try_insert_with(|| PersistentAppStateManager::load(self.app_path(app_id)))?;
+      .entry(app_id.to_string())
+      .or_try_insert_with(|| PersistentAppStateManager::load(self.app_path(app_id), self.default_quota))?;
     app.store_snapshot(storage, prefix).await
   }
 
-  fn drop_app(&self, id: &str) -> Result<()> {
+  async fn snapshot_info(
+    &self,
+    storage: &impl FileStorage,
+    prefix: &std::path::Path,
+  ) -> Result<SnapshotInfo> {
+    PersistentAppStateManager::snapshot_info(storage, prefix).await
+  }
+
+  async fn restore_snapshot(
+    &self,
+    app_id: &AppId,
+    storage: &impl FileStorage,
+    prefix: &std::path::Path,
+  ) -> Result<()> {
+    let app_path = self.app_path(app_id);
+    if app_path.exists() {
+      return Err(Error::AlreadyExists(format!("App {} already exists", app_id)));
+    }
+
+    // Download into a temporary directory so that a failed restore leaves no app behind
+    let tmp_path = self.root.join(format!(".restore-{}-{}", app_id, random_string(6)));
+    let result = PersistentAppStateManager::restore_snapshot(&tmp_path, storage, prefix).await;
+    if let Err(err) = result.and_then(|()| std::fs::rename(&tmp_path, &app_path).map_err(From::from)) {
+      let _ = std::fs::remove_dir_all(&tmp_path);
+      return Err(err);
+    }
+    sync_dir(&self.root)?;
+
+    self
+      .apps
+      .insert(
+        app_id.to_string(),
+        PersistentAppStateManager::load(app_path, self.default_quota)?,
+      );
+    Ok(())
+  }
+
+  fn drop_app(&self, id: &AppId) -> Result<()> {
     std::fs::remove_dir_all(self.app_path(id))?;
-    self.apps.remove(id);
+    self.apps.remove(id.as_str());
+    Ok(())
+  }
+
+  // Apps are loaded lazily, so the directory is listed instead of the loaded apps
+  fn list_apps(&self) -> Result<Vec<AppId>> {
+    let mut ids = Vec::new();
+    let entries = match std::fs::read_dir(&self.root) {
+      Ok(entries) => entries,
+      Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(ids),
+      Err(err) => return Err(err.into()),
+    };
+    for entry in entries {
+      let entry = entry?;
+      // Temporary directories of restores and forks are dot-prefixed, so they aren't valid ids
+      match entry.file_name().to_str().map(AppId::new) {
+        Some(Ok(id)) if entry.file_type()?.is_dir() => ids.push(id),
+        _ => {}
+      }
+    }
+    ids.sort();
+    Ok(ids)
+  }
+
+  fn fork_app(&self, source_id: &AppId, checkpoint_id: &str, id: &AppId) -> Result<()> {
+    let app_path = self.app_path(id);
+    if app_path.exists() {
+      return Err(Error::AlreadyExists(format!("App {} already exists", id)));
+    }
+
+    // The source is locked only while it's copied, since inserting the new app while
+    // holding it could deadlock the map
+    let tmp_path = self.root.join(format!(".fork-{}-{}", id, random_string(6)));
+    let result = self
+      .with_app(source_id, |app| app.fork(checkpoint_id, &tmp_path))
+      .and_then(|result| result)
+      .and_then(|()| std::fs::rename(&tmp_path, &app_path).map_err(From::from));
+    if let Err(err) = result {
+      let _ = std::fs::remove_dir_all(&tmp_path);
+      return Err(err);
+    }
+    sync_dir(&self.root)?;
+    info!("Forked {} from checkpoint {} of {}", id, checkpoint_id, source_id);
+
+    self.apps.insert(
+      id.to_string(),
+      PersistentAppStateManager::load(app_path, self.default_quota)?,
+    );
     Ok(())
   }
 }
 
 #[async_trait]
-impl<Storage: KVStorage> AppStateManager for PersistentAppStateManager<Storage> {
+impl AppStateManager for PersistentAppStateManager {
   fn get<Key: AsRef<str>>(&self, keys: &[Key]) -> Result<Vec<KeyValue>> {
     self.storage().get(keys)
   }
 
+  fn scan(&self, range: &KeyRange, limit: usize) -> Result<Vec<KeyValue>> {
+    self.storage().scan(range, limit)
+  }
+
   fn set(&mut self, parts: Vec<KeyValue>) -> Result<()> {
+    let usage = self.check_set(&parts)?;
+    let hash = self.hash_after(self.content_hash, content::set_values(&parts))?;
+    let changes = self.watchers.is_watched().then(|| watch::set_changes(&parts));
+    self.bump_modifications_number()?;
+    // The usage and the hash are unknown if the write fails halfway
+    self.usage = None;
+    self.content_hash = None;
     self.storage_mut().write(parts)?;
-    self.modifications_number += 1;
+    self.usage = usage;
+    self.content_hash = hash;
+    self.watchers.send(self.etag(), changes);
     Ok(())
   }
 
+  fn delete<Key: AsRef<str>>(&mut self, keys: &[Key]) -> Result<()> {
+    keys.iter().try_for_each(|key| validate_key(key.as_ref()))?;
+    let usage = self.usage_after(keys.iter().map(|key| (key.as_ref(), None)))?;
+    let hash = self.hash_after(self.content_hash, keys.iter().map(|key| (key.as_ref(), None)))?;
+    let changes = self.watchers.is_watched().then(|| watch::delete_changes(keys));
+    self.bump_modifications_number()?;
+    self.usage = None;
+    self.content_hash = None;
+    self.storage_mut().delete(keys)?;
+    self.usage = usage;
+    self.content_hash = hash;
+    self.watchers.send(self.etag(), changes);
+    Ok(())
+  }
+
+  fn get_at<Key: AsRef<str>>(&mut self, checkpoint_id: &str, keys: &[Key]) -> Result<Vec<KeyValue>> {
+    self.open_checkpoint(checkpoint_id)?.get(keys)
+  }
+
+  fn scan_at(
+    &mut self,
+    checkpoint_id: Option<&str>,
+    range: &KeyRange,
+    limit: usize,
+  ) -> Result<Vec<KeyValue>> {
+    match checkpoint_id {
+      Some(checkpoint_id) => self.open_checkpoint(checkpoint_id)?.scan(range, limit),
+      None => self.scan(range, limit),
+    }
+  }
+
   fn get_checkpoints(&self) -> Result<Vec<Checkpoint>> {
     Ok(self.manifest.checkpoints.clone())
   }
 
-  fn create_checkpoint(&mut self, payload: &str) -> Result<String> {
-    let ids = self.get_checkpoint_ids();
-    let (kept, removed) = if !ids.is_empty() {
-      crate::utils::exponential_sequence::extend(&ids)
-    } else {
-      (vec![0], vec![])
+  fn create_checkpoint(&mut self, payload: &str) -> Result<CreatedCheckpoint> {
+    let now = chrono::Utc::now();
+    let (new_id, evicted) = self.next_checkpoint(now)?;
+    // Unknown after a start if HEAD was modified since the last checkpoint
+    let hash = match self.content_hash {
+      Some(hash) => hash,
+      None => ContentHash::measure(|range, limit| self.scan(range, limit))?,
     };
-    let new_id = kept.last().unwrap().to_string();
-    let kept: HashSet<_> = kept.iter().map(|x| x.to_string()).collect();
+    self.content_hash = Some(hash);
+    self.bump_modifications_number()?;
 
-    self
-      .storage()
-      .save_copy(Self::checkpoint_path(&self.root, &new_id))?;
+    // An unrecorded directory is removed on load, so a crash before the manifest is saved
+    // leaves no partial checkpoint behind
+    let checkpoint_path = Self::checkpoint_path(&self.root, &new_id);
+    if checkpoint_path.exists() {
+      self.remove_checkpoint(&new_id)?;
+    }
+    let tmp_path = Self::checkpoint_path(&self.root, format!("{}.tmp", new_id));
+    if tmp_path.exists() {
+      std::fs::remove_dir_all(&tmp_path)?;
+    }
+    let parent_path = self
+      .manifest
+      .checkpoints
+      .last()
+      .map(|parent| Self::checkpoint_path(&self.root, &parent.id));
+    self.storage().save_checkpoint(&tmp_path, parent_path.as_deref())?;
+    sync_tree(&tmp_path)?;
+    let size = dir_size(&tmp_path)?;
+    std::fs::rename(&tmp_path, &checkpoint_path)?;
+    sync_dir(Self::checkpoints_dir(&self.root))?;
+    self.storage_mut().mark_checkpoint(&checkpoint_path)?;
 
     self.manifest.checkpoints.push(Checkpoint {
       id: new_id.clone(),
       payload: payload.to_owned(),
+      created_at: Some(now),
+      pinned: false,
+      key_count: Some(hash.keys),
+      size: Some(size),
+      modifications_number: Some(self.manifest.modifications_number),
+      content_hash: Some(hash.hex()),
+      usage: self.usage,
     });
-    self
-      .manifest
-      .checkpoints
-      .retain(|checkpoint| kept.contains(&checkpoint.id));
+    // The checkpoint completes a pending commit
+    self.manifest.pending_commit = false;
     self.save_manifest()?;
+    self.drop_checkpoints(|_, checkpoint| evicted.contains(&checkpoint.id))?;
+    Ok(CreatedCheckpoint {
+      id: new_id,
+      evicted,
+    })
+  }
 
-    for id in removed {
-      self.remove_checkpoint(&id.to_string())?;
+  fn commit(&mut self, parts: Vec<KeyValue>, payload: &str) -> Result<Committed> {
+    // Everything which may reject the commit is checked before anything is written
+    self.next_checkpoint(chrono::Utc::now())?;
+    let usage = self.check_set(&parts)?;
+
+    let pending = PendingCommit {
+      parts: parts.into_iter().map(|part| (part.key, part.value)).collect(),
+      spooled: false,
+      payload: Some(payload.to_owned()),
+    };
+    self.run_commit(pending, usage)
+  }
+
+  // Written like a commit, with the parts kept in the spool moved into the app's directory
+  fn bulk_set(&mut self, mut spool: Spool, checkpoint_payload: Option<&str>) -> Result<Committed> {
+    if checkpoint_payload.is_some() {
+      self.next_checkpoint(chrono::Utc::now())?;
     }
+    let usage = self.check_spool(&mut spool)?;
 
-    self.modifications_number += 1;
-    Ok(new_id)
+    let pending = PendingCommit {
+      parts: Vec::new(),
+      spooled: true,
+      payload: checkpoint_payload.map(str::to_owned),
+    };
+    spool.persist(Self::pending_spool_path(&self.root))?;
+    self.run_commit(pending, usage)
   }
 
   fn revert(&mut self, id: &str) -> Result<()> {
     let index = self.find_checkpoint(id)?;
+    self.discard_commit();
+    self.bump_modifications_number()?;
     self.reset_head(id)?;
-    self.remove_checkpoints((index + 1)..)?;
-    self.modifications_number += 1;
+    self.usage = self.manifest.checkpoints[index].usage;
+    self.content_hash = ContentHash::of_checkpoint(&self.manifest.checkpoints[index]);
+    self.remove_pending_commit()?;
+    self.drop_checkpoints(|i, _| i > index)?;
+    self.watchers.send_resync(self.etag());
     Ok(())
   }
 
   fn cleanup(&mut self, until_checkpoint: &str) -> Result<()> {
     let index = self.find_checkpoint(until_checkpoint)?;
-    self.remove_checkpoints(..index)?;
-    self.modifications_number += 1;
-    Ok(())
+    if let Some(pinned) = self.manifest.checkpoints[..index].iter().find(|cp| cp.pinned) {
+      return Err(pinned_checkpoint_error(pinned));
+    }
+    self.bump_modifications_number()?;
+    self.drop_checkpoints(|i, _| i < index)
+  }
+
+  fn set_pinned(&mut self, id: &str, pinned: bool) -> Result<()> {
+    let index = self.find_checkpoint(id)?;
+    self.bump_modifications_number()?;
+    self.manifest.checkpoints[index].pinned = pinned;
+    self.save_manifest()
   }
 
   fn reset(&mut self) -> Result<()> {
+    self.discard_commit();
+    self.bump_modifications_number()?;
     self.clean_head()?;
-    self.modifications_number += 1;
+    self.usage = Some(Usage::default());
+    self.content_hash = Some(ContentHash::default());
+    self.remove_pending_commit()?;
+    self.watchers.send_resync(self.etag());
     Ok(())
   }
 
+  fn watch(&self) -> ChangeReceiver {
+    self.watchers.subscribe()
+  }
+
+  fn quota(&self) -> Quota {
+    self.default_quota.with_overrides(&self.manifest.quota)
+  }
+
+  fn info(&self) -> Result<AppInfo> {
+    let key_count = match self.content_hash {
+      Some(hash) => Some(KeyCount {
+        count: hash.keys,
+        estimated: false,
+      }),
+      None => self.storage().key_count()?,
+    };
+    Ok(AppInfo {
+      backend: Some(self.backend()),
+      key_count: key_count.map(|key_count| key_count.count),
+      key_count_estimated: key_count.is_some_and(|key_count| key_count.estimated),
+      disk_usage: Some(dir_size(&self.root)?),
+      checkpoint_count: self.manifest.checkpoints.len(),
+      latest_checkpoint: self.manifest.checkpoints.last().cloned(),
+      last_write_at: self.manifest.last_write_at,
+    })
+  }
+
   async fn store_snapshot(
     &self,
     storage: &impl FileStorage,
@@ -340,16 +1181,47 @@ impl<Storage: KVStorage> AppStateManager for PersistentAppStateManager<Storage>
       "Can't create a snapshot because there are no checkpoints yet".to_owned(),
     ))?;
     let checkpoint_id = &checkpoint.id;
-    let checkpoint_path = Self::checkpoint_path(&self.root, checkpoint_id);
-    storage
-      .upload_folder(
-        &checkpoint_path,
-        &Self::checkpoint_path(prefix, checkpoint_id),
-      )
-      .await?;
+    // The checkpoint may depend on the previous ones, so a complete copy is uploaded.
+    // A leftover copy is removed on load as an unrecorded checkpoint
+    let copy_path = Self::checkpoint_path(&self.root, format!("{}.snapshot", checkpoint_id));
+    if copy_path.exists() {
+      AnyStorage::destroy(self.backend(), &copy_path)?;
+    }
+    AnyStorage::clone_dir(
+      self.backend(),
+      Self::checkpoint_path(&self.root, checkpoint_id),
+      &copy_path,
+    )?;
+    let result = async {
+      // Checkpoints created by older versions aren't hashed, so the complete copy is
+      let hash = match checkpoint.content_hash {
+        Some(_) => None,
+        None => {
+          let copy = AnyStorage::open_read_only(self.backend(), &copy_path)?;
+          Some(ContentHash::measure(|range, limit| copy.scan(range, limit))?)
+        }
+      };
+      storage
+        .upload_folder(&copy_path, &Self::checkpoint_path(prefix, checkpoint_id))
+        .await?;
+      Ok::<_, Error>(hash)
+    }
+    .await;
+    AnyStorage::destroy(self.backend(), &copy_path)?;
+    let hash = result?;
 
-    let mut manifest = AppManifest::default();
-    manifest.checkpoints.push(checkpoint.clone());
+    let mut manifest = AppManifest {
+      backend: self.manifest.backend,
+      retention: self.manifest.retention,
+      quota: self.manifest.quota,
+      ..Default::default()
+    };
+    let mut checkpoint = checkpoint.clone();
+    if let Some(hash) = hash {
+      checkpoint.key_count = Some(hash.keys);
+      checkpoint.content_hash = Some(hash.hex());
+    }
+    manifest.checkpoints.push(checkpoint);
     let manifest_contents: Vec<u8> = serde_json::to_string(&manifest)
       .map_err(std::io::Error::from)?
       .bytes()
@@ -360,7 +1232,11 @@ impl<Storage: KVStorage> AppStateManager for PersistentAppStateManager<Storage>
     Ok(())
   }
 
-  fn modifications_number(&self) -> u32 {
-    self.modifications_number
+  fn instance_id(&self) -> &str {
+    self.manifest.instance_id.as_deref().expect("Instance id is set on load")
+  }
+
+  fn modifications_number(&self) -> u64 {
+    self.manifest.modifications_number
   }
 }
@@ -0,0 +1,300 @@
+use super::interface::AppStateManager;
+use crate::types::{Error, KeyRange, KeyValue, Result};
+use crate::utils::spool::{Chunks, Spool, CHUNK_BYTES, CHUNK_KEYS};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+
+// Number of keys read at once when the usage of an app is measured
+const PAGE_SIZE: usize = 10_000;
+// Number of spooled keys whose sizes are kept in memory at once while the usage after
+// a bulk write is computed
+pub const SPOOL_BUCKET_KEYS: usize = 100_000;
+
+/// Limits of an app, `None` means unlimited.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Quota {
+  pub max_keys: Option<u64>,
+  // Total size of the values in bytes
+  pub max_bytes: Option<u64>,
+  pub max_value_size: Option<u64>,
+  pub max_checkpoints: Option<u64>,
+}
+
+fn exhausted(message: String) -> Error {
+  Error::ResourceExhausted(message)
+}
+
+impl Quota {
+  // Limits set in `overrides` replace the ones of `self`
+  pub fn with_overrides(self, overrides: &Quota) -> Self {
+    Self {
+      max_keys: overrides.max_keys.or(self.max_keys),
+      max_bytes: overrides.max_bytes.or(self.max_bytes),
+      max_value_size: overrides.max_value_size.or(self.max_value_size),
+      max_checkpoints: overrides.max_checkpoints.or(self.max_checkpoints),
+    }
+  }
+
+  // Whether the usage must be known to enforce the quota, measuring it may be expensive
+  pub fn limits_usage(&self) -> bool {
+    self.max_keys.is_some() || self.max_bytes.is_some()
+  }
+
+  pub fn check_values(&self, parts: &[KeyValue]) -> Result<()> {
+    let max = match self.max_value_size {
+      Some(max) => max,
+      None => return Ok(()),
+    };
+    match parts.iter().find(|part| part.value.len() as u64 > max) {
+      Some(part) => Err(exhausted(format!(
+        "Value of {:?} is {} bytes, the quota is {} bytes",
+        part.key,
+        part.value.len(),
+        max
+      ))),
+      None => Ok(()),
+    }
+  }
+
+  // Only writes increasing the usage fail, so that an app over a lowered quota can shrink
+  pub fn check_usage(&self, before: &Usage, after: &Usage) -> Result<()> {
+    if let Some(max) = self.max_keys {
+      if after.keys > max && after.keys > before.keys {
+        return Err(exhausted(format!(
+          "App would have {} keys, the quota is {}",
+          after.keys, max
+        )));
+      }
+    }
+    if let Some(max) = self.max_bytes {
+      if after.bytes > max && after.bytes > before.bytes {
+        return Err(exhausted(format!(
+          "App would have {} bytes of values, the quota is {}",
+          after.bytes, max
+        )));
+      }
+    }
+    Ok(())
+  }
+
+  // A bulk write can't add more keys or bytes of values than the app may hold, so that
+  // an oversized one is rejected while it's received rather than once it's spooled in full
+  pub fn check_bulk(&self, new_keys: &NewKeys) -> Result<()> {
+    if let Some(max) = self.max_keys {
+      if new_keys.count() > max {
+        return Err(exhausted(format!(
+          "Bulk write adds {} keys, the quota is {}",
+          new_keys.count(),
+          max
+        )));
+      }
+    }
+    if let Some(max) = self.max_bytes {
+      if new_keys.bytes > max {
+        return Err(exhausted(format!(
+          "Bulk write adds {} bytes of values, the quota is {}",
+          new_keys.bytes, max
+        )));
+      }
+    }
+    Ok(())
+  }
+
+  // `count` is the number of checkpoints once a new one is created and the evicted ones are removed
+  pub fn check_checkpoints(&self, count: usize) -> Result<()> {
+    match self.max_checkpoints {
+      Some(max) if count as u64 > max => Err(exhausted(format!(
+        "App would have {} checkpoints, the quota is {}",
+        count, max
+      ))),
+      _ => Ok(()),
+    }
+  }
+}
+
+fn key_hash(key: &str) -> u64 {
+  let mut hasher = DefaultHasher::new();
+  key.hash(&mut hasher);
+  hasher.finish()
+}
+
+/// Keys of a bulk write which aren't in the app yet, counted while the write is received.
+/// Only their hashes are kept, and the write fails once there are more than the quota of keys.
+#[derive(Debug, Default)]
+pub struct NewKeys {
+  sizes: HashMap<u64, u64>,
+  // Total size of the last values of the keys
+  bytes: u64,
+}
+
+impl NewKeys {
+  pub fn add(&mut self, key: &str, size: u64) {
+    if let Some(old) = self.sizes.insert(key_hash(key), size) {
+      self.bytes -= old;
+    }
+    self.bytes += size;
+  }
+
+  pub fn count(&self) -> u64 {
+    self.sizes.len() as u64
+  }
+}
+
+/// Number of keys and total size of the values of an app.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Usage {
+  pub keys: u64,
+  pub bytes: u64,
+}
+
+impl Usage {
+  pub fn measure(app: &impl AppStateManager) -> Result<Self> {
+    let mut usage = Self::default();
+    let mut range = KeyRange::default();
+    loop {
+      let page = app.scan(&range, PAGE_SIZE)?;
+      usage.keys += page.len() as u64;
+      usage.bytes += page.iter().map(|part| part.value.len() as u64).sum::<u64>();
+      match page.last() {
+        // The smallest key greater than the last one
+        Some(last) if page.len() == PAGE_SIZE => range.start = format!("{}\0", last.key),
+        _ => return Ok(usage),
+      }
+    }
+  }
+
+  // Usage after the keys are changed to the new value sizes, `None` for deleted keys.
+  // The last change of a key wins
+  pub fn after_write<'a>(
+    &self,
+    app: &impl AppStateManager,
+    changes: impl IntoIterator<Item = (&'a str, Option<u64>)>,
+  ) -> Result<Self> {
+    let changes: BTreeMap<_, _> = changes.into_iter().collect();
+    let keys: Vec<_> = changes.keys().copied().collect();
+    let old: HashMap<_, _> = app
+      .get(&keys)?
+      .into_iter()
+      .map(|part| (part.key, part.value.len() as u64))
+      .collect();
+
+    let mut usage = *self;
+    for (key, new) in changes {
+      let old = old.get(key).copied();
+      usage.keys = usage.keys + new.is_some() as u64 - old.is_some() as u64;
+      usage.bytes = usage.bytes + new.unwrap_or(0) - old.unwrap_or(0);
+    }
+    Ok(usage)
+  }
+
+  // Usage after the spooled parts are written. Their keys are split by hash into spools of
+  // about `bucket_keys` keys, so that the last size of every key is found without keeping
+  // all of them in memory
+  pub fn after_spool(
+    &self,
+    app: &impl AppStateManager,
+    spool: &mut Spool,
+    bucket_keys: usize,
+  ) -> Result<Self> {
+    let bucket_count = spool.count().div_ceil(bucket_keys as u64);
+    if bucket_count <= 1 {
+      let sizes = spooled_sizes(spool.chunks(CHUNK_KEYS, CHUNK_BYTES)?, |value| Ok(value.len() as u64))?;
+      return self.after_write(app, sizes.iter().map(|(key, size)| (key.as_str(), Some(*size))));
+    }
+
+    // Buckets hold the sizes of the values in place of the values
+    let mut buckets = (0..bucket_count)
+      .map(|_| Spool::create(spool.dir()))
+      .collect::<std::io::Result<Vec<_>>>()?;
+    for chunk in spool.chunks(CHUNK_KEYS, CHUNK_BYTES)? {
+      for part in chunk? {
+        let bucket = &mut buckets[(key_hash(&part.key) % bucket_count) as usize];
+        bucket.push(&part.key, &(part.value.len() as u64).to_le_bytes())?;
+      }
+    }
+    // Buckets don't share keys, so each of them changes the usage independently
+    let mut usage = *self;
+    for mut bucket in buckets {
+      let sizes = spooled_sizes(bucket.chunks(CHUNK_KEYS, CHUNK_BYTES)?, |value| {
+        let size = value
+          .try_into()
+          .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid size"))?;
+        Ok(u64::from_le_bytes(size))
+      })?;
+      usage = usage.after_write(app, sizes.iter().map(|(key, size)| (key.as_str(), Some(*size))))?;
+    }
+    Ok(usage)
+  }
+}
+
+// Keys of the spooled parts with the sizes `size` reads from their values
+fn spooled_sizes(
+  chunks: Chunks,
+  size: impl Fn(&[u8]) -> std::io::Result<u64>,
+) -> Result<Vec<(String, u64)>> {
+  let mut sizes = Vec::new();
+  for chunk in chunks {
+    for part in chunk? {
+      sizes.push((part.key, size(&part.value)?));
+    }
+  }
+  Ok(sizes)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_quota() {
+    let defaults = Quota {
+      max_keys: Some(10),
+      max_value_size: Some(4),
+      ..Default::default()
+    };
+    let overrides = Quota {
+      max_keys: Some(2),
+      max_bytes: Some(100),
+      ..Default::default()
+    };
+    let quota = defaults.with_overrides(&overrides);
+    assert_eq!(quota.max_keys, Some(2));
+    assert_eq!(quota.max_bytes, Some(100));
+    assert_eq!(quota.max_value_size, Some(4));
+    assert_eq!(quota.max_checkpoints, None);
+    assert!(quota.limits_usage());
+    assert!(!Quota::default().limits_usage());
+
+    let part = |value: &str| KeyValue {
+      key: "a".to_owned(),
+      value: value.as_bytes().to_vec(),
+    };
+    assert!(quota.check_values(&[part("abcd")]).is_ok());
+    assert!(matches!(
+      quota.check_values(&[part("abcde")]),
+      Err(Error::ResourceExhausted(_))
+    ));
+
+    let usage = |keys, bytes| Usage { keys, bytes };
+    assert!(quota.check_usage(&usage(1, 0), &usage(2, 0)).is_ok());
+    assert!(quota.check_usage(&usage(2, 0), &usage(3, 0)).is_err());
+    // An app over the quota can shrink
+    assert!(quota.check_usage(&usage(5, 0), &usage(4, 0)).is_ok());
+    assert!(quota.check_usage(&usage(0, 100), &usage(0, 101)).is_err());
+    assert!(quota.check_checkpoints(1000).is_ok());
+
+    let mut new_keys = NewKeys::default();
+    new_keys.add("a", 50);
+    new_keys.add("b", 60);
+    new_keys.add("b", 50);
+    assert_eq!((new_keys.count(), new_keys.bytes), (2, 100));
+    assert!(quota.check_bulk(&new_keys).is_ok());
+    new_keys.add("b", 51);
+    assert!(quota.check_bulk(&new_keys).is_err());
+    new_keys.add("b", 0);
+    new_keys.add("c", 0);
+    assert!(quota.check_bulk(&new_keys).is_err());
+  }
+}
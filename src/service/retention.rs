@@ -0,0 +1,158 @@
+use super::interface::Checkpoint;
+use crate::utils::exponential_sequence;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+/// Decides which checkpoints are evicted after a new one is created.
+pub trait RetentionPolicy {
+  // `checkpoints` are ordered by creation, the last one is the new checkpoint, which is never
+  // evicted. Returns ids of the evicted checkpoints
+  fn evict(&self, checkpoints: &[Checkpoint], now: DateTime<Utc>) -> HashSet<String>;
+}
+
+/// Keeps more recent checkpoints than old ones, so that the number of checkpoints
+/// grows logarithmically. Requires numeric checkpoint ids.
+pub struct Exponential;
+
+impl RetentionPolicy for Exponential {
+  fn evict(&self, checkpoints: &[Checkpoint], _now: DateTime<Utc>) -> HashSet<String> {
+    let ids: Vec<i32> = checkpoints[..checkpoints.len().saturating_sub(1)]
+      .iter()
+      .map(|checkpoint| {
+        checkpoint
+          .id
+          .parse()
+          .expect("Non numerical checkpoint name in manifest")
+      })
+      .collect();
+    if ids.is_empty() {
+      return HashSet::new();
+    }
+    let (_kept, removed) = exponential_sequence::extend(&ids);
+    removed.into_iter().map(|id| id.to_string()).collect()
+  }
+}
+
+/// Keeps the `count` latest checkpoints.
+pub struct KeepLast {
+  pub count: usize,
+}
+
+impl RetentionPolicy for KeepLast {
+  fn evict(&self, checkpoints: &[Checkpoint], _now: DateTime<Utc>) -> HashSet<String> {
+    let evicted = checkpoints.len().saturating_sub(self.count.max(1));
+    checkpoints[..evicted]
+      .iter()
+      .map(|checkpoint| checkpoint.id.clone())
+      .collect()
+  }
+}
+
+/// Keeps the checkpoints created within `max_age`. Checkpoints without
+/// a creation time are never evicted.
+pub struct KeepFor {
+  pub max_age: chrono::Duration,
+}
+
+impl RetentionPolicy for KeepFor {
+  fn evict(&self, checkpoints: &[Checkpoint], now: DateTime<Utc>) -> HashSet<String> {
+    let old = checkpoints.split_last().map_or(&[][..], |(_new, old)| old);
+    old
+      .iter()
+      .filter(|checkpoint| {
+        checkpoint
+          .created_at
+          .is_some_and(|created_at| now - created_at > self.max_age)
+      })
+      .map(|checkpoint| checkpoint.id.clone())
+      .collect()
+  }
+}
+
+/// Never evicts anything, checkpoints are removed only by `Cleanup` and `Revert`.
+pub struct KeepAll;
+
+impl RetentionPolicy for KeepAll {
+  fn evict(&self, _checkpoints: &[Checkpoint], _now: DateTime<Utc>) -> HashSet<String> {
+    HashSet::new()
+  }
+}
+
+/// Retention policy of an app, stored in its manifest.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Retention {
+  #[default]
+  Exponential,
+  KeepLast { count: usize },
+  KeepFor { max_age_seconds: u64 },
+  KeepAll,
+}
+
+impl Retention {
+  pub fn policy(&self) -> Box<dyn RetentionPolicy> {
+    match *self {
+      Self::Exponential => Box::new(Exponential),
+      Self::KeepLast { count } => Box::new(KeepLast { count }),
+      Self::KeepFor { max_age_seconds } => Box::new(KeepFor {
+        max_age: chrono::Duration::seconds(max_age_seconds.min(i64::MAX as u64 / 1000) as i64),
+      }),
+      Self::KeepAll => Box::new(KeepAll),
+    }
+  }
+
+  // Ids of the checkpoints evicted once a checkpoint with `new_id` is created `now`, in the order
+  // of `checkpoints`. Pinned checkpoints are never evicted
+  pub fn evicted(&self, checkpoints: &[Checkpoint], new_id: &str, now: DateTime<Utc>) -> Vec<String> {
+    let mut with_new = checkpoints.to_vec();
+    with_new.push(Checkpoint {
+      id: new_id.to_owned(),
+      created_at: Some(now),
+      ..Default::default()
+    });
+    let evicted = self.policy().evict(&with_new, now);
+    checkpoints
+      .iter()
+      .filter(|checkpoint| !checkpoint.pinned && evicted.contains(&checkpoint.id))
+      .map(|checkpoint| checkpoint.id.clone())
+      .collect()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn checkpoints(ages: &[i64], now: DateTime<Utc>) -> Vec<Checkpoint> {
+    ages
+      .iter()
+      .enumerate()
+      .map(|(i, age)| Checkpoint {
+        id: i.to_string(),
+        created_at: Some(now - chrono::Duration::seconds(*age)),
+        ..Default::default()
+      })
+      .collect()
+  }
+
+  fn evict(retention: Retention, checkpoints: &[Checkpoint], now: DateTime<Utc>) -> Vec<String> {
+    let mut evicted: Vec<_> = retention.policy().evict(checkpoints, now).into_iter().collect();
+    evicted.sort();
+    evicted
+  }
+
+  #[test]
+  fn test_policies() {
+    let now = Utc::now();
+    let checkpoints = checkpoints(&[50, 40, 30, 20, 10], now);
+    assert_eq!(evict(Retention::Exponential, &checkpoints, now), ["2"]);
+    assert_eq!(evict(Retention::KeepLast { count: 2 }, &checkpoints, now), ["0", "1", "2"]);
+    assert_eq!(evict(Retention::KeepLast { count: 0 }, &checkpoints, now).len(), 4);
+    let keep_for = Retention::KeepFor { max_age_seconds: 25 };
+    assert_eq!(evict(keep_for, &checkpoints, now), ["0", "1", "2"]);
+    // The new checkpoint is kept even if it's too old
+    assert_eq!(evict(keep_for, &checkpoints[4..], now + chrono::Duration::seconds(60)).len(), 0);
+    assert!(evict(Retention::KeepAll, &checkpoints, now).is_empty());
+  }
+}
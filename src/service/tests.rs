@@ -1,6 +1,68 @@
+use super::content::ContentHash;
+use super::diff::KeyDiff;
 use super::in_memory::InMemoryStateManager;
-use super::interface::{AppStateManager, Checkpoint, StateManager};
-use crate::types::KeyValue;
+use crate::file_storage::interface::{FileInfo, FileStorage};
+use super::interface::{AppOptions, AppStateManager, Checkpoint, Committed, StateManager};
+use super::persistent::PersistentStateManager;
+use super::quota::{Quota, Usage};
+use super::retention::Retention;
+use super::watch::{KeyChange, MAX_EVENT_CHANGES};
+use crate::storage::any::Backend;
+use crate::storage::filesystem::FilesystemStorage;
+use crate::storage::interface::KVStorage;
+use crate::types::{AppId, Error, KeyRange, KeyValue, Result};
+use crate::utils::spool::Spool;
+use std::path::{Path, PathBuf};
+
+// Snapshot storage backed by a local directory
+pub struct DirFileStorage {
+  pub root: PathBuf,
+}
+
+impl DirFileStorage {
+  fn local_path(&self, remote_path: &Path) -> PathBuf {
+    self.root.join(remote_path.strip_prefix("/").unwrap_or(remote_path))
+  }
+}
+
+#[async_trait::async_trait]
+impl FileStorage for DirFileStorage {
+  async fn upload_file(&self, path: &Path, remote_path: &Path) -> Result<()> {
+    self.upload_buffer(&std::fs::read(path)?, remote_path).await
+  }
+
+  async fn upload_buffer(&self, bytes: &[u8], remote_path: &Path) -> Result<()> {
+    let path = self.local_path(remote_path);
+    std::fs::create_dir_all(path.parent().unwrap())?;
+    std::fs::write(path, bytes)?;
+    Ok(())
+  }
+
+  async fn download_file(&self, remote_path: &Path, path: &Path) -> Result<()> {
+    std::fs::copy(self.local_path(remote_path), path)?;
+    Ok(())
+  }
+
+  async fn download_buffer(&self, remote_path: &Path) -> Result<Vec<u8>> {
+    Ok(std::fs::read(self.local_path(remote_path))?)
+  }
+
+  async fn list(&self, remote_path: &Path) -> Result<Vec<FileInfo>> {
+    let root = self.local_path(remote_path);
+    let mut result = Vec::new();
+    for entry in walkdir::WalkDir::new(&root).into_iter().flatten() {
+      if entry.file_type().is_file() {
+        let metadata = entry.metadata().unwrap();
+        result.push(FileInfo {
+          path: entry.path().strip_prefix(&root).unwrap().to_owned(),
+          size: metadata.len(),
+          last_modified: metadata.modified()?.into(),
+        });
+      }
+    }
+    Ok(result)
+  }
+}
 
 fn part(key: impl AsRef<str>, value: impl AsRef<[u8]>) -> KeyValue {
   KeyValue {
@@ -10,14 +72,14 @@ fn part(key: impl AsRef<str>, value: impl AsRef<[u8]>) -> KeyValue {
 }
 
 fn test_service(manager: &impl StateManager) {
-  const APP_ID: &str = "test";
-  manager.init_app(APP_ID).unwrap();
+  let app_id = AppId::new("test").unwrap();
+  manager.init_app(&app_id, &Default::default()).unwrap();
   manager
-    .with_app(APP_ID, |app| {
+    .with_app(&app_id, |app| {
       assert_eq!(app.modifications_number(), 0);
       assert!(app.get_checkpoints().unwrap().is_empty());
 
-      let checkpoint0 = app.create_checkpoint("0").unwrap();
+      let checkpoint0 = app.create_checkpoint("0").unwrap().id;
       app.revert(&checkpoint0).unwrap();
       assert!(app.get(&["a", "b", "c"]).unwrap().is_empty());
       app.set(vec![part("a", "0"), part("b", "0")]).unwrap();
@@ -27,20 +89,20 @@ fn test_service(manager: &impl StateManager) {
         vec![part("a", "1"), part("b", "0")]
       );
 
-      let checkpoint1 = app.create_checkpoint("1").unwrap();
+      let checkpoint1 = app.create_checkpoint("1").unwrap().id;
       assert_eq!(
         app.get(&["a", "b", "c"]).unwrap(),
         vec![part("a", "1"), part("b", "0")]
       );
       app.set(vec![part("a", "2"), part("c", "2")]).unwrap();
       app.cleanup(&checkpoint1).unwrap();
-      assert_eq!(
-        app.get_checkpoints().unwrap(),
-        vec![Checkpoint {
-          id: checkpoint1.clone(),
-          payload: "1".to_owned()
-        }]
-      );
+      let checkpoints: Vec<_> = app
+        .get_checkpoints()
+        .unwrap()
+        .into_iter()
+        .map(|checkpoint| (checkpoint.id, checkpoint.payload))
+        .collect();
+      assert_eq!(checkpoints, vec![(checkpoint1.clone(), "1".to_owned())]);
       assert!(app.revert(&checkpoint0).is_err());
       assert_eq!(
         app.get(&["a", "b", "c"]).unwrap(),
@@ -53,13 +115,828 @@ fn test_service(manager: &impl StateManager) {
         vec![part("a", "1"), part("b", "0")]
       );
 
-      assert_eq!(app.modifications_number(), 8);
+      app.delete(&["a", "c"]).unwrap();
+      assert_eq!(app.get(&["a", "b", "c"]).unwrap(), vec![part("b", "0")]);
+      app.revert(&checkpoint1).unwrap();
+      assert_eq!(
+        app.get(&["a", "b", "c"]).unwrap(),
+        vec![part("a", "1"), part("b", "0")]
+      );
+
+      app.delete(&["b"]).unwrap();
+      let checkpoint2 = app.create_checkpoint("2").unwrap().id;
+      assert_eq!(app.get(&["a", "b", "c"]).unwrap(), vec![part("a", "1")]);
+      app.set(vec![part("b", "3")]).unwrap();
+      app.revert(&checkpoint2).unwrap();
+      assert_eq!(app.get(&["a", "b", "c"]).unwrap(), vec![part("a", "1")]);
+      app.revert(&checkpoint1).unwrap();
+      assert_eq!(
+        app.get(&["a", "b", "c"]).unwrap(),
+        vec![part("a", "1"), part("b", "0")]
+      );
+
+      assert_eq!(app.modifications_number(), 15);
+    })
+    .unwrap();
+}
+
+fn test_scan(manager: &impl StateManager) {
+  let app_id = AppId::new("test_scan").unwrap();
+  manager.init_app(&app_id, &Default::default()).unwrap();
+  manager
+    .with_app(&app_id, |app| {
+      app
+        .set(vec![
+          part("a", "0"),
+          part("b/1", "1"),
+          part("b/2", "2"),
+          part("b/3", "3"),
+          part("c", "4"),
+        ])
+        .unwrap();
+      app.create_checkpoint("0").unwrap();
+      app.set(vec![part("b/0", "5"), part("b/2", "6")]).unwrap();
+      app.delete(&["b/3"]).unwrap();
+
+      let range = KeyRange {
+        prefix: "b/".to_owned(),
+        ..Default::default()
+      };
+      assert_eq!(
+        app.scan(&range, 10).unwrap(),
+        vec![part("b/0", "5"), part("b/1", "1"), part("b/2", "6")]
+      );
+      assert_eq!(
+        app.scan(&range, 2).unwrap(),
+        vec![part("b/0", "5"), part("b/1", "1")]
+      );
+
+      let range = KeyRange {
+        prefix: "b/".to_owned(),
+        start: "b/1".to_owned(),
+        end: Some("b/2".to_owned()),
+      };
+      assert_eq!(app.scan(&range, 10).unwrap(), vec![part("b/1", "1")]);
+
+      let range = KeyRange {
+        start: "b/2".to_owned(),
+        ..Default::default()
+      };
+      assert_eq!(
+        app.scan(&range, 10).unwrap(),
+        vec![part("b/2", "6"), part("c", "4")]
+      );
+    })
+    .unwrap();
+}
+
+// Checkpoints store only the changes, so they must stay intact when others are removed
+fn test_checkpoint_chain(manager: &impl StateManager) {
+  let app_id = AppId::new("test_chain").unwrap();
+  manager.init_app(&app_id, &Default::default()).unwrap();
+  manager
+    .with_app(&app_id, |app| {
+      let expected = |i: usize| {
+        let mut values = vec![part("counter", i.to_string()), part(format!("key/{}", i), "")];
+        values.extend((i + 1..20).map(|j| part(format!("static/{:02}", j), "")));
+        values.sort_by(|a, b| a.key.cmp(&b.key));
+        values
+      };
+      app
+        .set((0..20).map(|j| part(format!("static/{:02}", j), "")).collect())
+        .unwrap();
+      for i in 0..20usize {
+        app.set(vec![part("counter", i.to_string()), part(format!("key/{}", i), "")]).unwrap();
+        app.delete(&[format!("key/{}", i.wrapping_sub(1)), format!("static/{:02}", i)]).unwrap();
+        app.create_checkpoint(&i.to_string()).unwrap();
+      }
+      let checkpoints = app.get_checkpoints().unwrap();
+      let middle = &checkpoints[checkpoints.len() / 2];
+      app.cleanup(&middle.id).unwrap();
+
+      for checkpoint in app.get_checkpoints().unwrap() {
+        assert_eq!(
+          app.get_at(&checkpoint.id, &["counter", "static/00"]).unwrap(),
+          vec![part("counter", &checkpoint.payload)]
+        );
+      }
+      for checkpoint in app.get_checkpoints().unwrap().iter().rev() {
+        app.revert(&checkpoint.id).unwrap();
+        let i = checkpoint.payload.parse().unwrap();
+        assert_eq!(app.scan(&KeyRange::default(), 100).unwrap(), expected(i));
+      }
+
+      // Ids of reverted checkpoints may be reused
+      app.set(vec![part("counter", "new")]).unwrap();
+      let checkpoint = app.create_checkpoint("").unwrap();
+      assert_eq!(
+        app.get_at(&checkpoint.id, &["counter"]).unwrap(),
+        vec![part("counter", "new")]
+      );
+    })
+    .unwrap();
+}
+
+fn test_pinned(manager: &impl StateManager) {
+  let app_id = AppId::new("test_pinned").unwrap();
+  let options = AppOptions {
+    retention: Some(Retention::KeepLast { count: 1 }),
+    ..Default::default()
+  };
+  manager.init_app(&app_id, &options).unwrap();
+  manager
+    .with_app(&app_id, |app| {
+      app.set(vec![part("a", "0")]).unwrap();
+      let pinned = app.create_checkpoint("0").unwrap().id;
+      app.set_pinned(&pinned, true).unwrap();
+      app.set(vec![part("a", "1")]).unwrap();
+      let first = app.create_checkpoint("1").unwrap();
+      assert!(first.evicted.is_empty());
+      let second = app.create_checkpoint("2").unwrap();
+      assert_eq!(second.evicted, vec![first.id]);
+
+      assert!(matches!(app.cleanup(&second.id), Err(Error::FailedPrecondition(_))));
+      app.revert(&pinned).unwrap();
+      assert_eq!(app.get(&["a"]).unwrap(), vec![part("a", "0")]);
+      app.create_checkpoint("3").unwrap();
+      app.set_pinned(&pinned, false).unwrap();
+      let last = app.create_checkpoint("4").unwrap();
+      assert_eq!(last.evicted.len(), 2);
+      assert!(app.set_pinned(&pinned, true).is_err());
+    })
+    .unwrap();
+}
+
+fn test_diff(manager: &impl StateManager) {
+  let app_id = AppId::new("test_diff").unwrap();
+  manager.init_app(&app_id, &Default::default()).unwrap();
+  manager
+    .with_app(&app_id, |app| {
+      // More keys than a page of the diff
+      let parts = (0..2500).map(|i| part(format!("k/{:04}", i), "0")).collect();
+      app.set(parts).unwrap();
+      let first = app.create_checkpoint("").unwrap().id;
+      app.set(vec![part("k/0500", "1"), part("k/2000", "0"), part("new", "1")]).unwrap();
+      app.delete(&["k/1500"]).unwrap();
+      let second = app.create_checkpoint("").unwrap().id;
+      app.delete(&["new"]).unwrap();
+
+      let diff = app.diff(Some(&first), Some(&second), &KeyRange::default(), 10).unwrap();
+      let expected = vec![
+        KeyDiff {
+          key: "k/0500".to_owned(),
+          old: Some(b"0".to_vec()),
+          new: Some(b"1".to_vec()),
+        },
+        KeyDiff {
+          key: "k/1500".to_owned(),
+          old: Some(b"0".to_vec()),
+          new: None,
+        },
+        KeyDiff {
+          key: "new".to_owned(),
+          old: None,
+          new: Some(b"1".to_vec()),
+        },
+      ];
+      assert_eq!(diff, expected);
+      let range = KeyRange {
+        prefix: "k/".to_owned(),
+        ..Default::default()
+      };
+      assert_eq!(app.diff(Some(&first), Some(&second), &range, 1).unwrap(), expected[..1]);
+      assert_eq!(app.diff(Some(&second), None, &KeyRange::default(), 10).unwrap().len(), 1);
+      assert!(app.diff(Some(&first), Some(&first), &KeyRange::default(), 10).unwrap().is_empty());
+      assert!(app.diff(Some("missing"), None, &KeyRange::default(), 10).is_err());
+    })
+    .unwrap();
+}
+
+fn test_fork(manager: &impl StateManager) {
+  let source_id = AppId::new("test_fork_source").unwrap();
+  let fork_id = AppId::new("test_fork").unwrap();
+  manager.init_app(&source_id, &Default::default()).unwrap();
+  let checkpoint = manager
+    .with_app(&source_id, |app| {
+      app.set(vec![part("a", "0"), part("b", "0")]).unwrap();
+      app.create_checkpoint("").unwrap();
+      app.set(vec![part("a", "1")]).unwrap();
+      let checkpoint = app.create_checkpoint("forked").unwrap().id;
+      app.delete(&["a"]).unwrap();
+      checkpoint
+    })
+    .unwrap();
+
+  let result = manager.fork_app(&source_id, "missing", &fork_id);
+  assert!(matches!(result, Err(Error::NotFound(_))));
+  assert!(manager.with_app(&fork_id, |_app| ()).is_err());
+  manager.fork_app(&source_id, &checkpoint, &fork_id).unwrap();
+  let result = manager.fork_app(&source_id, &checkpoint, &fork_id);
+  assert!(matches!(result, Err(Error::AlreadyExists(_))));
+
+  manager
+    .with_app(&fork_id, |app| {
+      assert_eq!(app.get(&["a", "b"]).unwrap(), vec![part("a", "1"), part("b", "0")]);
+      let checkpoints = app.get_checkpoints().unwrap();
+      assert_eq!(checkpoints.len(), 1);
+      assert_eq!(checkpoints[0].id, checkpoint);
+      assert_eq!(checkpoints[0].payload, "forked");
+      app.set(vec![part("b", "1")]).unwrap();
+      let new_checkpoint = app.create_checkpoint("").unwrap().id;
+      assert_ne!(new_checkpoint, checkpoint);
+    })
+    .unwrap();
+  manager
+    .with_app(&source_id, |app| {
+      assert_eq!(app.get(&["a", "b"]).unwrap(), vec![part("b", "0")]);
+      assert_eq!(app.get_checkpoints().unwrap().len(), 2);
     })
     .unwrap();
 }
 
+fn test_app_info(manager: &impl StateManager) {
+  let app_id = AppId::new("test_app_info").unwrap();
+  manager.init_app(&app_id, &Default::default()).unwrap();
+  manager
+    .with_app(&app_id, |app| {
+      let info = app.info().unwrap();
+      assert_eq!(info.key_count, Some(0));
+      assert_eq!(info.checkpoint_count, 0);
+      assert_eq!(info.latest_checkpoint, None);
+      assert_eq!(info.last_write_at, None);
+      assert_eq!(info.disk_usage.is_some(), info.backend.is_some());
+
+      app.set(vec![part("a", "0"), part("b", "0")]).unwrap();
+      let checkpoint = app.create_checkpoint("").unwrap().id;
+      app.delete(&["a"]).unwrap();
+      let info = app.info().unwrap();
+      assert_eq!(info.key_count, Some(1));
+      assert_eq!(info.checkpoint_count, 1);
+      assert_eq!(info.latest_checkpoint.unwrap().id, checkpoint);
+      assert!(info.last_write_at.is_some());
+    })
+    .unwrap();
+
+  let ids = manager.list_apps().unwrap();
+  assert!(ids.contains(&app_id));
+  assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+}
+
+fn test_quota(manager: &impl StateManager) {
+  let app_id = AppId::new("test_quota").unwrap();
+  let quota = Quota {
+    max_keys: Some(2),
+    max_bytes: Some(6),
+    max_value_size: Some(4),
+    max_checkpoints: Some(2),
+  };
+  let options = AppOptions {
+    retention: Some(Retention::KeepAll),
+    quota: Some(quota),
+    ..Default::default()
+  };
+  manager.init_app(&app_id, &options).unwrap();
+  let exhausted = |result: Result<_>| matches!(result, Err(Error::ResourceExhausted(_)));
+  manager
+    .with_app(&app_id, |app| {
+      assert!(exhausted(app.set(vec![part("a", "12345")])));
+      app.set(vec![part("a", "1234"), part("b", "12")]).unwrap();
+      assert!(exhausted(app.set(vec![part("b", "123")])));
+      assert!(exhausted(app.set(vec![part("c", "")])));
+      let first = app.create_checkpoint("").unwrap().id;
+      app.delete(&["a"]).unwrap();
+      app.set(vec![part("c", "")]).unwrap();
+      app.create_checkpoint("").unwrap();
+      assert!(exhausted(app.create_checkpoint("").map(|_| ())));
+      assert_eq!(app.get_checkpoints().unwrap().len(), 2);
+
+      // Checkpoints record the usage, which is restored with HEAD
+      let checkpoints = app.get_checkpoints().unwrap();
+      assert_eq!(checkpoints[0].usage, Some(Usage { keys: 2, bytes: 6 }));
+      assert_eq!(checkpoints[1].usage, Some(Usage { keys: 2, bytes: 2 }));
+      app.revert(&first).unwrap();
+      assert!(exhausted(app.set(vec![part("c", "")])));
+      assert!(exhausted(app.set(vec![part("b", "123")])));
+      app.set(vec![part("b", "")]).unwrap();
+      app.set(vec![part("b", "12")]).unwrap();
+
+      // Spooled keys are counted once, whichever bucket they're in
+      let mut spool = Spool::create(std::env::temp_dir()).unwrap();
+      for (key, value) in [("a", "1"), ("c", "12"), ("d", ""), ("a", "123"), ("c", "1")] {
+        spool.push(key, value.as_bytes()).unwrap();
+      }
+      let before = Usage { keys: 2, bytes: 6 };
+      for bucket_keys in [1, 2, 100] {
+        let after = before.after_spool(app, &mut spool, bucket_keys).unwrap();
+        assert_eq!(after, Usage { keys: 4, bytes: 6 });
+      }
+    })
+    .unwrap();
+
+  // An app over a lowered quota can still overwrite and delete keys
+  let options = AppOptions {
+    quota: Some(Quota {
+      max_keys: Some(1),
+      ..quota
+    }),
+    ..Default::default()
+  };
+  manager.init_app(&app_id, &options).unwrap();
+  manager
+    .with_app(&app_id, |app| {
+      app.set(vec![part("a", "1")]).unwrap();
+      assert!(exhausted(app.set(vec![part("c", "")])));
+      app.delete(&["a"]).unwrap();
+      app.set(vec![part("b", "1")]).unwrap();
+    })
+    .unwrap();
+}
+
+fn test_commit(manager: &impl StateManager) {
+  let app_id = AppId::new("test_commit").unwrap();
+  let options = AppOptions {
+    retention: Some(Retention::KeepAll),
+    quota: Some(Quota {
+      max_checkpoints: Some(1),
+      ..Default::default()
+    }),
+    ..Default::default()
+  };
+  manager.init_app(&app_id, &options).unwrap();
+  manager
+    .with_app(&app_id, |app| {
+      // Neither the parts nor the checkpoint are applied if one of them is rejected
+      let result = app.commit(vec![part("a", "0"), part("", "")], "offset-0");
+      assert!(matches!(result, Err(Error::InvalidArgument(_))));
+      assert!(app.get(&["a"]).unwrap().is_empty());
+      assert!(app.get_checkpoints().unwrap().is_empty());
+
+      let checkpoint = match app.commit(vec![part("a", "1")], "offset-1").unwrap() {
+        Committed::Applied(Some(checkpoint)) => checkpoint,
+        committed => panic!("Unexpected {:?}", committed),
+      };
+      let checkpoints = app.get_checkpoints().unwrap();
+      assert_eq!(checkpoints.len(), 1);
+      assert_eq!(checkpoints[0].id, checkpoint.id);
+      assert_eq!(checkpoints[0].payload, "offset-1");
+      assert_eq!(app.get_at(&checkpoint.id, &["a"]).unwrap(), vec![part("a", "1")]);
+
+      let result = app.commit(vec![part("b", "1")], "offset-2");
+      assert!(matches!(result, Err(Error::ResourceExhausted(_))));
+      assert!(app.get(&["b"]).unwrap().is_empty());
+      assert_eq!(app.get_checkpoints().unwrap().len(), 1);
+    })
+    .unwrap();
+}
+
+fn test_watch(manager: &impl StateManager) {
+  use tokio::sync::broadcast::error::TryRecvError;
+
+  let app_id = AppId::new("test_watch").unwrap();
+  manager.init_app(&app_id, &Default::default()).unwrap();
+  let change = |key: &str, value: Option<&str>| KeyChange {
+    key: key.to_owned(),
+    value: value.map(|value| value.as_bytes().to_vec()),
+  };
+  let mut receiver = manager
+    .with_app(&app_id, |app| {
+      let mut receiver = app.watch();
+      app.set(vec![part("a", "0"), part("b", "0")]).unwrap();
+      let event = receiver.try_recv().unwrap();
+      assert_eq!(event.changes, [change("a", Some("0")), change("b", Some("0"))]);
+      assert_eq!(event.etag, app.etag());
+
+      let checkpoint = app.create_checkpoint("").unwrap();
+      app.set(vec![part("a", "1"), part("c", "1")]).unwrap();
+      app.delete(&["b"]).unwrap();
+      let event = receiver.try_recv().unwrap();
+      assert_eq!(event.changes, [change("a", Some("1")), change("c", Some("1"))]);
+      let event = receiver.try_recv().unwrap();
+      assert_eq!(event.changes, [change("b", None)]);
+
+      // Replacing HEAD makes the watchers read the keys again
+      app.revert(&checkpoint.id).unwrap();
+      let event = receiver.try_recv().unwrap();
+      assert!(event.resync && event.changes.is_empty());
+      assert_eq!(event.etag, app.etag());
+      let etag = app.etag();
+      app.reset().unwrap();
+      assert_ne!(app.etag(), etag);
+      let event = receiver.try_recv().unwrap();
+      assert!(event.resync);
+      assert_eq!(event.etag, app.etag());
+
+      // Too many changes for one event
+      let parts: Vec<_> = (0..=MAX_EVENT_CHANGES).map(|i| part(format!("many/{}", i), "")).collect();
+      app.set(parts).unwrap();
+      let event = receiver.try_recv().unwrap();
+      assert!(event.resync && event.changes.is_empty());
+      assert!(matches!(receiver.try_recv(), Err(TryRecvError::Empty)));
+      receiver
+    })
+    .unwrap();
+  manager.drop_app(&app_id).unwrap();
+  assert!(matches!(receiver.try_recv(), Err(TryRecvError::Closed)));
+}
+
+fn checkpoint_metadata(manager: &impl StateManager) -> Checkpoint {
+  let app_id = AppId::new("test_metadata").unwrap();
+  manager.init_app(&app_id, &Default::default()).unwrap();
+  manager
+    .with_app(&app_id, |app| {
+      app.set(vec![part("a", "0"), part("b", "1"), part("c", "2")]).unwrap();
+      app.create_checkpoint("").unwrap();
+      app.delete(&["c"]).unwrap();
+      app.create_checkpoint("").unwrap();
+      let checkpoint = app.get_checkpoints().unwrap().pop().unwrap();
+      assert_eq!(checkpoint.modifications_number, Some(app.modifications_number()));
+      assert!(checkpoint.created_at.is_some());
+      assert_eq!(checkpoint.key_count, Some(2));
+
+      // Equal contents have equal hashes, however they were written
+      app.set(vec![part("c", "3"), part("c", "2")]).unwrap();
+      app.create_checkpoint("").unwrap();
+      let checkpoints = app.get_checkpoints().unwrap();
+      assert_eq!(checkpoints[2].content_hash, checkpoints[0].content_hash);
+      app.revert(&checkpoints[1].id).unwrap();
+      app.delete(&["a", "b"]).unwrap();
+      app.create_checkpoint("").unwrap();
+      let empty = app.get_checkpoints().unwrap().pop().unwrap();
+      assert_eq!(empty.content_hash, Some(ContentHash::default().hex()));
+      assert_eq!(empty.key_count, Some(0));
+      app.revert(&checkpoints[1].id).unwrap();
+      checkpoint
+    })
+    .unwrap()
+}
+
+#[test]
+fn test_checkpoint_metadata() {
+  let dir = tempfile::tempdir().unwrap();
+  let path = dir.path();
+  let persistent = checkpoint_metadata(&PersistentStateManager::new(path, Backend::Filesystem));
+  let in_memory = checkpoint_metadata(&InMemoryStateManager::default());
+  // HEAD modified since the last checkpoint is hashed in full after a restart
+  PersistentStateManager::new(path, Backend::Filesystem)
+    .with_app(&AppId::new("test_metadata").unwrap(), |app| {
+      assert_eq!(app.info().unwrap().key_count, Some(2));
+      app.set(vec![part("c", "2")]).unwrap();
+    })
+    .unwrap();
+  PersistentStateManager::new(path, Backend::Filesystem)
+    .with_app(&AppId::new("test_metadata").unwrap(), |app| {
+      app.create_checkpoint("").unwrap();
+      let checkpoint = app.get_checkpoints().unwrap().pop().unwrap();
+      let entries = [("a", b"0".as_slice()), ("b", b"1".as_slice()), ("c", b"2".as_slice())];
+      assert_eq!(checkpoint.content_hash, Some(ContentHash::of(entries).hex()));
+      assert_eq!(checkpoint.key_count, Some(3));
+    })
+    .unwrap();
+  assert_eq!(in_memory.key_count, persistent.key_count);
+  let hash = ContentHash::of([("a", b"0".as_slice()), ("b", b"1".as_slice())]);
+  assert_eq!(persistent.content_hash, Some(hash.hex()));
+  assert_eq!(in_memory.content_hash, Some(hash.hex()));
+  assert!(persistent.size.unwrap() > 0);
+  assert_eq!(in_memory.size, None);
+}
+
 #[test]
 fn test_basic() {
   let manager = InMemoryStateManager::default();
   test_service(&manager);
+  test_scan(&manager);
+  test_checkpoint_chain(&manager);
+  test_pinned(&manager);
+  test_diff(&manager);
+  test_fork(&manager);
+  test_app_info(&manager);
+  test_quota(&manager);
+  test_commit(&manager);
+  test_watch(&manager);
+}
+
+#[test]
+fn test_persistent() {
+  let dir = tempfile::tempdir().unwrap();
+  let path = dir.path();
+  let manager = PersistentStateManager::new(path, Backend::Filesystem);
+  test_service(&manager);
+  test_scan(&manager);
+  test_checkpoint_chain(&manager);
+  test_pinned(&manager);
+  test_diff(&manager);
+  test_fork(&manager);
+  test_app_info(&manager);
+  test_quota(&manager);
+  test_commit(&manager);
+  test_watch(&manager);
+  let app_id = AppId::new("test").unwrap();
+  for invalid_id in ["", "../etc", ".restore", "a/b", "ä"] {
+    assert!(AppId::new(invalid_id).is_err());
+  }
+  let result = manager.with_app(&app_id, |app| app.set(vec![part("", "")])).unwrap();
+  assert!(matches!(result, Err(Error::InvalidArgument(_))));
+
+  // The backend of an existing app can't be changed
+  let options = AppOptions {
+    backend: Some(Backend::Rocksdb),
+    ..Default::default()
+  };
+  assert!(matches!(
+    manager.init_app(&app_id, &options),
+    Err(Error::FailedPrecondition(_))
+  ));
+  let options = AppOptions {
+    backend: Some(Backend::Filesystem),
+    ..Default::default()
+  };
+  manager.init_app(&app_id, &options).unwrap();
+
+  let etag = manager.with_app(&app_id, |app| app.etag()).unwrap();
+
+  // Reloaded apps keep their backend even if the default one changes, and their etag
+  drop(manager);
+  let manager = PersistentStateManager::new(path, Backend::Rocksdb);
+  // Apps are listed before they are loaded, but temporary directories aren't
+  std::fs::create_dir(path.join(".fork-test-abcdef")).unwrap();
+  let ids = manager.list_apps().unwrap();
+  assert!(ids.contains(&app_id));
+  assert!(ids.iter().all(|id| !id.as_str().starts_with('.')));
+  manager.init_app(&app_id, &Default::default()).unwrap();
+  manager.with_app(&app_id, |app| app.get(&["a"]).unwrap()).unwrap();
+  assert_eq!(manager.with_app(&app_id, |app| app.etag()).unwrap(), etag);
+
+  // A recreated app doesn't reuse etags
+  manager.drop_app(&app_id).unwrap();
+  manager.init_app(&app_id, &options).unwrap();
+  assert_ne!(manager.with_app(&app_id, |app| app.etag()).unwrap(), etag);
+
+  let app_id = AppId::new("test_retention").unwrap();
+  let options = AppOptions {
+    backend: Some(Backend::Filesystem),
+    retention: Some(Retention::KeepLast { count: 1 }),
+    ..Default::default()
+  };
+  manager.init_app(&app_id, &options).unwrap();
+  drop(manager);
+  let manager = PersistentStateManager::new(path, Backend::Filesystem);
+  manager
+    .with_app(&app_id, |app| {
+      let first = app.create_checkpoint("").unwrap();
+      let second = app.create_checkpoint("").unwrap();
+      assert_eq!(second.evicted, vec![first.id]);
+      assert_eq!(app.get_checkpoints().unwrap().len(), 1);
+    })
+    .unwrap();
+}
+
+// Reverts, cleanups and forks of RocksDB apps hardlink the checkpoint files
+#[test]
+fn test_rocksdb() {
+  let dir = tempfile::tempdir().unwrap();
+  let path = dir.path();
+  let manager = PersistentStateManager::new(path, Backend::Rocksdb);
+  test_checkpoint_chain(&manager);
+  test_fork(&manager);
+}
+
+#[test]
+fn test_legacy_backend() {
+  let dir = tempfile::tempdir().unwrap();
+  let path = dir.path();
+  let app_id = AppId::new("legacy").unwrap();
+  let manager = PersistentStateManager::new(path, Backend::Filesystem);
+  manager.init_app(&app_id, &Default::default()).unwrap();
+  manager
+    .with_app(&app_id, |app| app.set(vec![part("a", "0")]).unwrap())
+    .unwrap();
+  drop(manager);
+  // Apps created before backends could be chosen, with and without a manifest
+  let manifest_path = path.join("legacy").join("manifest.json");
+  let mut manifest: serde_json::Value =
+    serde_json::from_str(&std::fs::read_to_string(&manifest_path).unwrap()).unwrap();
+  manifest.as_object_mut().unwrap().remove("backend");
+  std::fs::write(&manifest_path, manifest.to_string()).unwrap();
+  std::fs::create_dir_all(path.join("bare").join("checkpoints")).unwrap();
+  // HEAD of an older version, which stored keys as is
+  let head_path = path.join("bare").join("HEAD");
+  std::fs::create_dir_all(head_path.join("dir")).unwrap();
+  std::fs::write(head_path.join("%41"), "1").unwrap();
+  std::fs::write(head_path.join("dir").join(".key"), "2").unwrap();
+
+  // They keep the filesystem backend even if the server's default one is different
+  let manager = PersistentStateManager::new(path, Backend::Rocksdb);
+  for id in ["legacy", "bare"] {
+    let app_id = AppId::new(id).unwrap();
+    manager.init_app(&app_id, &Default::default()).unwrap();
+    let info = manager.with_app(&app_id, |app| app.info().unwrap()).unwrap();
+    assert_eq!(info.backend, Some(Backend::Filesystem));
+  }
+  let parts = manager.with_app(&app_id, |app| app.get(&["a"]).unwrap()).unwrap();
+  assert_eq!(parts, vec![part("a", "0")]);
+  // It's rewritten in the current format, so it can be written
+  let bare_id = AppId::new("bare").unwrap();
+  manager
+    .with_app(&bare_id, |app| {
+      assert_eq!(
+        app.scan(&KeyRange::default(), 10).unwrap(),
+        vec![part("%41", "1"), part("dir/.key", "2")]
+      );
+      app.set(vec![part("b", "3")]).unwrap();
+    })
+    .unwrap();
+  drop(manager);
+  let manager = PersistentStateManager::new(path, Backend::Filesystem);
+  let parts = manager.with_app(&bare_id, |app| app.get(&["%41", "b"]).unwrap()).unwrap();
+  assert_eq!(parts, vec![part("%41", "1"), part("b", "3")]);
+  drop(manager);
+  let manifest = std::fs::read_to_string(manifest_path).unwrap();
+  assert!(manifest.contains(r#""backend":"filesystem""#));
+}
+
+#[test]
+fn test_persistent_recovery() {
+  let dir = tempfile::tempdir().unwrap();
+  let path = dir.path();
+  let app_id = AppId::new("test").unwrap();
+  let app_path = path.join("test");
+  let manager = PersistentStateManager::new(path, Backend::Filesystem);
+  manager.init_app(&app_id, &Default::default()).unwrap();
+  let checkpoint = manager
+    .with_app(&app_id, |app| {
+      app.set(vec![part("a", "0")]).unwrap();
+      let checkpoint = app.create_checkpoint("").unwrap().id;
+      app.set(vec![part("a", "1")]).unwrap();
+      checkpoint
+    })
+    .unwrap();
+  drop(manager);
+  let checkpoint_path = app_path.join("checkpoints").join(&checkpoint);
+
+  // Crashed after the old HEAD was moved away during a revert
+  std::fs::rename(app_path.join("HEAD"), app_path.join("HEAD.old")).unwrap();
+  FilesystemStorage::open(&checkpoint_path)
+    .unwrap()
+    .save_copy(app_path.join("HEAD.new"))
+    .unwrap();
+  // Crashed while creating a checkpoint
+  std::fs::create_dir(app_path.join("checkpoints").join("1.tmp")).unwrap();
+
+  let manager = PersistentStateManager::new(path, Backend::Filesystem);
+  manager
+    .with_app(&app_id, |app| {
+      assert_eq!(app.get(&["a"]).unwrap(), vec![part("a", "0")]);
+      assert_eq!(app.get_checkpoints().unwrap().len(), 1);
+    })
+    .unwrap();
+  drop(manager);
+  for name in ["HEAD.new", "HEAD.old", "checkpoints/1.tmp"] {
+    assert!(!app_path.join(name).exists());
+  }
+
+  // Crashed while building the new HEAD, before anything was replaced
+  std::fs::create_dir(app_path.join("HEAD.new")).unwrap();
+  let manager = PersistentStateManager::new(path, Backend::Filesystem);
+  manager
+    .with_app(&app_id, |app| {
+      assert_eq!(app.get(&["a"]).unwrap(), vec![part("a", "0")]);
+    })
+    .unwrap();
+  assert!(!app_path.join("HEAD.new").exists());
+
+  // Crashed after writing a part of a commit, before its checkpoint was saved
+  manager
+    .with_app(&app_id, |app| app.set(vec![part("a", "2")]).unwrap())
+    .unwrap();
+  drop(manager);
+  let commit_path = app_path.join("commit.json");
+  std::fs::write(&commit_path, r#"{"parts": [["b", [51]], ["c", [51]]], "payload": "offset"}"#).unwrap();
+  FilesystemStorage::open(app_path.join("HEAD"))
+    .unwrap()
+    .write(vec![part("b", "3")])
+    .unwrap();
+  let manifest_path = app_path.join("manifest.json");
+  let mut manifest: serde_json::Value =
+    serde_json::from_str(&std::fs::read_to_string(&manifest_path).unwrap()).unwrap();
+  manifest["pending_commit"] = true.into();
+  std::fs::write(&manifest_path, manifest.to_string()).unwrap();
+  let manager = PersistentStateManager::new(path, Backend::Filesystem);
+  manager
+    .with_app(&app_id, |app| {
+      // The commit is finished, and the writes acknowledged before it are kept
+      assert_eq!(
+        app.get(&["a", "b", "c"]).unwrap(),
+        vec![part("a", "2"), part("b", "3"), part("c", "3")]
+      );
+      let checkpoints = app.get_checkpoints().unwrap();
+      assert_eq!(checkpoints.len(), 2);
+      assert_eq!(checkpoints[1].payload, "offset");
+    })
+    .unwrap();
+  drop(manager);
+  assert!(!commit_path.exists());
+
+  // Crashed after the checkpoint of a commit was saved
+  std::fs::write(&commit_path, r#"{"parts": [["d", [51]]], "payload": ""}"#).unwrap();
+  let manager = PersistentStateManager::new(path, Backend::Filesystem);
+  manager
+    .with_app(&app_id, |app| {
+      assert!(app.get(&["d"]).unwrap().is_empty());
+      assert_eq!(app.get_checkpoints().unwrap().len(), 2);
+      app.reset().unwrap();
+    })
+    .unwrap();
+  drop(manager);
+  assert!(!commit_path.exists());
+
+  // Crashed while building the new HEAD of a revert following a reset, which left no HEAD
+  assert!(!app_path.join("HEAD").exists());
+  FilesystemStorage::open(&checkpoint_path)
+    .unwrap()
+    .save_copy(app_path.join("HEAD.new"))
+    .unwrap();
+  let manager = PersistentStateManager::new(path, Backend::Filesystem);
+  manager
+    .with_app(&app_id, |app| {
+      assert!(app.get(&["a", "b", "c"]).unwrap().is_empty());
+      assert_eq!(app.get_checkpoints().unwrap().len(), 2);
+    })
+    .unwrap();
+  assert!(!app_path.join("HEAD.new").exists());
+
+  // A pending commit which can't be finished, since the quota was lowered, blocks the app
+  // until a reset discards it
+  let options = AppOptions {
+    retention: Some(Retention::KeepAll),
+    quota: Some(Quota {
+      max_checkpoints: Some(2),
+      ..Default::default()
+    }),
+    ..Default::default()
+  };
+  manager.init_app(&app_id, &options).unwrap();
+  drop(manager);
+  std::fs::write(&commit_path, r#"{"parts": [["e", [49]]], "payload": "over"}"#).unwrap();
+  let mut manifest: serde_json::Value =
+    serde_json::from_str(&std::fs::read_to_string(&manifest_path).unwrap()).unwrap();
+  manifest["pending_commit"] = true.into();
+  std::fs::write(&manifest_path, manifest.to_string()).unwrap();
+  let manager = PersistentStateManager::new(path, Backend::Filesystem);
+  for _ in 0..2 {
+    let result = manager.with_app(&app_id, |app| app.get(&["e"]).unwrap());
+    assert!(matches!(result, Err(Error::FailedPrecondition(_))));
+  }
+  manager
+    .with_app_replacing_head(&app_id, |app| app.reset().unwrap())
+    .unwrap();
+  assert!(!commit_path.exists());
+  drop(manager);
+  let manager = PersistentStateManager::new(path, Backend::Filesystem);
+  manager
+    .with_app(&app_id, |app| {
+      assert!(app.get(&["e"]).unwrap().is_empty());
+      assert_eq!(app.get_checkpoints().unwrap().len(), 2);
+    })
+    .unwrap();
+}
+
+#[tokio::test]
+async fn test_snapshot_restore() {
+  let dir = tempfile::tempdir().unwrap();
+  let app_id = AppId::new("test").unwrap();
+  let storage = DirFileStorage {
+    root: dir.path().join("storage"),
+  };
+  let prefix = Path::new("/snapshots/test/0");
+
+  let source = PersistentStateManager::new(dir.path().join("0"), Backend::Filesystem);
+  source.init_app(&app_id, &Default::default()).unwrap();
+  let checkpoints = source
+    .with_app(&app_id, |app| {
+      app.set(vec![part("a", "0"), part("b", "1")]).unwrap();
+      app.create_checkpoint("0").unwrap();
+      app.set(vec![part("a", "2")]).unwrap();
+      app.get_checkpoints().unwrap()
+    })
+    .unwrap();
+  source.store_snapshot(&app_id, &storage, prefix).await.unwrap();
+
+  let target = PersistentStateManager::new(dir.path().join("1"), Backend::Filesystem);
+  target.restore_snapshot(&app_id, &storage, prefix).await.unwrap();
+  assert!(target.restore_snapshot(&app_id, &storage, prefix).await.is_err());
+  let info = target.snapshot_info(&storage, prefix).await.unwrap();
+  let checkpoint = checkpoints.last().unwrap().clone();
+  let hash = ContentHash::of([("a", b"0".as_slice()), ("b", b"1".as_slice())]);
+  assert_eq!(checkpoint.content_hash, Some(hash.hex()));
+  assert_eq!(info.checkpoint, checkpoint);
+  assert!(info.size > 0);
+  target
+    .with_app(&app_id, |app| {
+      assert_eq!(app.get_checkpoints().unwrap(), [checkpoint]);
+      assert_eq!(
+        app.get(&["a", "b"]).unwrap(),
+        vec![part("a", "0"), part("b", "1")]
+      );
+    })
+    .unwrap();
 }
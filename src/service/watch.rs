@@ -0,0 +1,101 @@
+use crate::types::{Bytes, KeyValue};
+use std::sync::Arc;
+use tokio::sync::broadcast;
+
+// Number of events kept for watchers which haven't read them yet
+const CAPACITY: usize = 1024;
+// Larger modifications send a resync event instead of their changes, so that an event
+// never holds a large part of the app in memory
+pub const MAX_EVENT_CHANGES: usize = 1000;
+
+/// New value of a key, `None` if it was deleted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyChange {
+  pub key: String,
+  pub value: Option<Bytes>,
+}
+
+/// Keys changed by a modification of an app.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChangeEvent {
+  // Etag of the app after the modification
+  pub etag: String,
+  pub changes: Vec<KeyChange>,
+  // The changes weren't collected, e.g. because HEAD was replaced, so the keys must be read again
+  pub resync: bool,
+}
+
+pub type ChangeReceiver = broadcast::Receiver<Arc<ChangeEvent>>;
+
+/// Sends the changes of an app to its watchers. Their receivers are closed when the app is dropped.
+#[derive(Debug)]
+pub struct Watchers {
+  sender: broadcast::Sender<Arc<ChangeEvent>>,
+}
+
+impl Default for Watchers {
+  fn default() -> Self {
+    let (sender, _receiver) = broadcast::channel(CAPACITY);
+    Self { sender }
+  }
+}
+
+impl Watchers {
+  pub fn subscribe(&self) -> ChangeReceiver {
+    self.sender.subscribe()
+  }
+
+  // Changes may be expensive to collect, so they are collected only if there are watchers
+  pub fn is_watched(&self) -> bool {
+    self.sender.receiver_count() > 0
+  }
+
+  pub fn send(&self, etag: String, changes: Option<Vec<KeyChange>>) {
+    match changes {
+      Some(changes) if changes.len() > MAX_EVENT_CHANGES => self.send_resync(etag),
+      Some(changes) if !changes.is_empty() => self.send_event(ChangeEvent {
+        etag,
+        changes,
+        resync: false,
+      }),
+      _ => (),
+    }
+  }
+
+  // Tells the watchers to read the keys again instead of sending the changes, e.g. when HEAD
+  // is replaced, since collecting them would read the whole app
+  pub fn send_resync(&self, etag: String) {
+    if self.is_watched() {
+      self.send_event(ChangeEvent {
+        etag,
+        changes: Vec::new(),
+        resync: true,
+      });
+    }
+  }
+
+  fn send_event(&self, event: ChangeEvent) {
+    // Fails only if all the watchers are gone
+    let _ = self.sender.send(Arc::new(event));
+  }
+}
+
+pub fn set_changes(parts: &[KeyValue]) -> Vec<KeyChange> {
+  parts
+    .iter()
+    .map(|part| KeyChange {
+      key: part.key.clone(),
+      value: Some(part.value.clone()),
+    })
+    .collect()
+}
+
+pub fn delete_changes<Key: AsRef<str>>(keys: &[Key]) -> Vec<KeyChange> {
+  keys
+    .iter()
+    .map(|key| KeyChange {
+      key: key.as_ref().to_owned(),
+      value: None,
+    })
+    .collect()
+}
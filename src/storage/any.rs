@@ -0,0 +1,102 @@
+use super::filesystem::FilesystemStorage;
+use super::interface::{KVStorage, KeyCount};
+use super::rocksdb::RocksdbStorage;
+use crate::types::{KeyRange, KeyValue, Result};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+  Filesystem,
+  Rocksdb,
+}
+
+/// Storage of any backend, so that apps with different backends can be
+/// managed together
+pub enum AnyStorage {
+  Filesystem(FilesystemStorage),
+  Rocksdb(RocksdbStorage),
+}
+
+macro_rules! dispatch {
+  ($self:expr, $storage:ident => $body:expr) => {
+    match $self {
+      AnyStorage::Filesystem($storage) => $body,
+      AnyStorage::Rocksdb($storage) => $body,
+    }
+  };
+}
+
+impl AnyStorage {
+  pub fn open(backend: Backend, path: impl AsRef<Path>) -> Result<Self> {
+    Ok(match backend {
+      Backend::Filesystem => Self::Filesystem(FilesystemStorage::open(path)?),
+      Backend::Rocksdb => Self::Rocksdb(RocksdbStorage::open(path)?),
+    })
+  }
+
+  pub fn open_read_only(backend: Backend, path: impl AsRef<Path>) -> Result<Self> {
+    Ok(match backend {
+      Backend::Filesystem => Self::Filesystem(FilesystemStorage::open_read_only(path)?),
+      Backend::Rocksdb => Self::Rocksdb(RocksdbStorage::open_read_only(path)?),
+    })
+  }
+
+  pub fn destroy(backend: Backend, path: impl AsRef<Path>) -> Result<()> {
+    match backend {
+      Backend::Filesystem => FilesystemStorage::destroy(path),
+      Backend::Rocksdb => RocksdbStorage::destroy(path),
+    }
+  }
+
+  pub fn clone_dir(backend: Backend, src: impl AsRef<Path>, dst: impl AsRef<Path>) -> Result<()> {
+    match backend {
+      Backend::Filesystem => FilesystemStorage::clone_dir(src, dst),
+      Backend::Rocksdb => RocksdbStorage::clone_dir(src, dst),
+    }
+  }
+
+  pub fn rebase_checkpoint(backend: Backend, path: &Path, new_parent: Option<&Path>) -> Result<()> {
+    match backend {
+      Backend::Filesystem => FilesystemStorage::rebase_checkpoint(path, new_parent),
+      Backend::Rocksdb => RocksdbStorage::rebase_checkpoint(path, new_parent),
+    }
+  }
+
+  pub fn get<Key: AsRef<str>>(&self, keys: &[Key]) -> Result<Vec<KeyValue>> {
+    dispatch!(self, storage => storage.get(keys))
+  }
+
+  pub fn scan(&self, range: &KeyRange, limit: usize) -> Result<Vec<KeyValue>> {
+    dispatch!(self, storage => storage.scan(range, limit))
+  }
+
+  pub fn write(&mut self, parts: Vec<KeyValue>) -> Result<()> {
+    dispatch!(self, storage => storage.write(parts))
+  }
+
+  pub fn delete<Key: AsRef<str>>(&mut self, keys: &[Key]) -> Result<()> {
+    dispatch!(self, storage => storage.delete(keys))
+  }
+
+  pub fn key_count(&self) -> Result<Option<KeyCount>> {
+    dispatch!(self, storage => storage.key_count())
+  }
+
+  pub fn is_legacy(&self) -> bool {
+    dispatch!(self, storage => storage.is_legacy())
+  }
+
+  pub fn save_copy(&self, path: impl AsRef<Path>) -> Result<()> {
+    dispatch!(self, storage => storage.save_copy(path))
+  }
+
+  pub fn save_checkpoint(&self, path: impl AsRef<Path>, parent: Option<&Path>) -> Result<()> {
+    dispatch!(self, storage => storage.save_checkpoint(path, parent))
+  }
+
+  pub fn mark_checkpoint(&mut self, path: &Path) -> Result<()> {
+    dispatch!(self, storage => storage.mark_checkpoint(path))
+  }
+}
@@ -1,36 +1,558 @@
-use super::interface;
-use crate::types::{Bytes, Error, KeyValue, Result};
-use std::collections::hash_map::HashMap;
-use std::path::Path;
+use super::interface::{self, KeyCount};
+use crate::types::{validate_key, Bytes, Error, KeyRange, KeyValue, Result};
+use crate::utils::fs::{sync_dir, write_atomic};
+use log::{error, warn};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+// Internal files start with a dot, which is always escaped in the names of key files
+const WAL_FILENAME: &str = ".wal";
+// Changed keys are dumped to files and the log is truncated after it grows over this size
+const WAL_COMPACTION_SIZE: u64 = 64 * 1024 * 1024;
+// Name of the checkpoint which a checkpoint stores the changes against. Complete ones don't have it
+const PARENT_FILENAME: &str = ".parent";
+// Keys deleted since the parent checkpoint
+const DELETED_FILENAME: &str = ".deleted";
+// Longest chain of checkpoints storing changes down to a complete one. The next checkpoint is
+// complete, so that opening a checkpoint reads a bounded number of them
+const MAX_DELTA_CHAIN: usize = 16;
+// The last checkpoint of the storage and the keys changed since it, apart from the ones in the log
+const CHANGED_FILENAME: &str = ".changed";
+// Written when a directory is created, before any key. Directories written before key
+// filenames were encoded don't have it, their filenames are the keys as is. Such a directory
+// could have a key with the same name, but not with the same contents
+const FORMAT_FILENAME: &str = ".format";
+const FORMAT_VERSION: &str = "state-manager filesystem storage 2\n";
+// Only these dot-files are internal in directories without a format, other ones are keys
+const LEGACY_INTERNAL_FILENAMES: [&str; 4] =
+  [WAL_FILENAME, PARENT_FILENAME, DELETED_FILENAME, CHANGED_FILENAME];
+
+const PUT: u8 = 0;
+const DELETE: u8 = 1;
+
+// A key with its new value or `None` if the key is deleted
+type Change = (String, Option<Bytes>);
+
+// Characters which are kept as is in filenames, everything else is percent-encoded
+const FILENAME_CHARS: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'_').remove(b'.');
+// Encoded keys are split into nested directories to fit into the filename length limit
+const MAX_FILENAME_LEN: usize = 200;
+// Marks directories holding parts of long keys. Never appears in encoded keys
+const PART_SUFFIX: char = '+';
+
+/// Relative path of the file storing `key`. Any key is turned into a safe path:
+/// no separators, no `.` and `..` components and no names starting with a dot.
+fn key_path(key: &str) -> PathBuf {
+  let encoded = utf8_percent_encode(key, FILENAME_CHARS).to_string();
+  let mut path = PathBuf::new();
+  let mut rest = encoded.as_str();
+  loop {
+    let mut len = rest.len();
+    if len > MAX_FILENAME_LEN {
+      len = MAX_FILENAME_LEN;
+      // Don't split an escape sequence, the encoded key is ASCII
+      if let Some(offset) = rest[len - 2..len].find('%') {
+        len = len - 2 + offset;
+      }
+    }
+    let (part, tail) = rest.split_at(len);
+    let mut name = match part.strip_prefix('.') {
+      Some(part) => format!("%2E{}", part),
+      None => part.to_owned(),
+    };
+    rest = tail;
+    if rest.is_empty() {
+      path.push(name);
+      return path;
+    }
+    name.push(PART_SUFFIX);
+    path.push(name);
+  }
+}
+
+/// Inverse of `key_path`, returns `None` for paths it couldn't have produced
+fn path_key(path: &Path) -> Option<String> {
+  let mut encoded = String::new();
+  let mut components = path.iter().peekable();
+  while let Some(component) = components.next() {
+    let component = component.to_str()?;
+    if components.peek().is_some() {
+      encoded.push_str(component.strip_suffix(PART_SUFFIX)?);
+    } else {
+      encoded.push_str(component);
+    }
+  }
+  let key = percent_decode_str(&encoded).decode_utf8().ok()?;
+  Some(key.into_owned())
+}
+
+/// Key of a file in a directory without a format, where keys were stored as is
+fn legacy_path_key(path: &Path) -> Option<String> {
+  let components: Option<Vec<_>> = path.iter().map(|component| component.to_str()).collect();
+  Some(components?.join("/"))
+}
+
+// Marks a new directory with the current format, before any key is written to it
+fn create_dir(path: &Path) -> Result<()> {
+  std::fs::create_dir(path)?;
+  write_file(&path.join(FORMAT_FILENAME), FORMAT_VERSION.as_bytes())
+}
+
+// Whether an existing directory was written without the format file. A directory whose
+// format file was cut by a crash has no keys yet, so it's fine to read it either way
+fn is_legacy_dir(path: &Path) -> Result<bool> {
+  let format = read_optional(&path.join(FORMAT_FILENAME))?;
+  Ok(path.exists() && format.as_deref() != Some(FORMAT_VERSION.as_bytes()))
+}
+
+#[derive(Serialize, Deserialize)]
+struct ChangedKeys {
+  checkpoint: PathBuf,
+  keys: BTreeSet<String>,
+}
+
+// Files of a storage directory
+#[derive(Default)]
+struct Delta {
+  values: BTreeMap<String, Bytes>,
+  deleted: BTreeSet<String>,
+  parent: Option<String>,
+  // Written without a format file, see `FORMAT_FILENAME`
+  legacy: bool,
+}
+
+impl Delta {
+  fn read(path: &Path) -> Result<Self> {
+    let mut delta = Self {
+      legacy: is_legacy_dir(path)?,
+      ..Self::default()
+    };
+    if !path.exists() {
+      return Ok(delta);
+    }
+    let legacy = delta.legacy;
+    // Encoded keys never start with a dot, but keys stored as is could
+    let entries = WalkDir::new(path)
+      .min_depth(1)
+      .into_iter()
+      .filter_entry(|entry| {
+        let name = entry.file_name().to_string_lossy();
+        match legacy {
+          true => entry.depth() > 1 || !LEGACY_INTERNAL_FILENAMES.contains(&name.as_ref()),
+          false => !name.starts_with('.'),
+        }
+      });
+    for entry in entries {
+      let entry = entry.map_err(std::io::Error::from)?;
+      if !entry.file_type().is_file() {
+        continue;
+      }
+      let relative_path = entry.path().strip_prefix(path).unwrap();
+      let key = match legacy {
+        true => legacy_path_key(relative_path),
+        false => path_key(relative_path),
+      };
+      let key = key.ok_or_else(|| {
+        Error::DbError(format!("Unexpected file {}", entry.path().display()))
+      })?;
+      let value: Bytes = std::fs::read(entry.path())?;
+      delta.values.insert(key, value);
+    }
+    if let Some(contents) = read_optional(&path.join(DELETED_FILENAME))? {
+      delta.deleted = serde_json::from_slice(&contents).map_err(std::io::Error::from)?;
+    }
+    delta.parent = read_parent(path)?;
+    Ok(delta)
+  }
+
+  // Contents of the checkpoint at `path`, which is applied on top of its ancestors
+  fn read_chain(path: &Path) -> Result<Self> {
+    let mut chain = vec![Self::read(path)?];
+    let mut names = BTreeSet::new();
+    while let Some(parent) = chain.last().unwrap().parent.clone() {
+      if !names.insert(parent.clone()) {
+        return Err(Error::DbError(format!("Cyclic parents of {}", path.display())));
+      }
+      chain.push(Self::read(&path.with_file_name(parent))?);
+    }
+    let mut result = Self {
+      legacy: chain[0].legacy,
+      ..Self::default()
+    };
+    for delta in chain.into_iter().rev() {
+      for key in &delta.deleted {
+        result.values.remove(key);
+      }
+      result.values.extend(delta.values);
+    }
+    Ok(result)
+  }
+}
+
+// Name of the checkpoint which the one at `path` stores the changes against
+fn read_parent(path: &Path) -> Result<Option<String>> {
+  match read_optional(&path.join(PARENT_FILENAME))? {
+    Some(contents) => String::from_utf8(contents)
+      .map(Some)
+      .map_err(|err| Error::DbError(format!("Invalid parent of {}: {}", path.display(), err))),
+    None => Ok(None),
+  }
+}
+
+// Number of checkpoints storing changes from `path` down to a complete one, at most `MAX_DELTA_CHAIN`
+fn chain_length(path: &Path) -> Result<usize> {
+  let mut length = 0;
+  let mut current = path.to_owned();
+  while length < MAX_DELTA_CHAIN {
+    match read_parent(&current)? {
+      Some(parent) => current = path.with_file_name(parent),
+      None => break,
+    }
+    length += 1;
+  }
+  Ok(length)
+}
 
 /// Simple storage implementation which caches all the values in memory and
 /// dumps them to files as a checkpoint.
 /// Effective only for cases with a small amount of keys.
+///
+/// Every write is appended to a write-ahead log inside the storage directory
+/// and synced to disk before returning, so acknowledged writes survive restarts.
+///
+/// Checkpoints store only the keys changed since the previous checkpoint and
+/// refer to it by name, so they must be in the same directory. Chains of such
+/// checkpoints are cut by complete ones, see `MAX_DELTA_CHAIN`.
+///
+/// Directories written by older versions, see `FORMAT_FILENAME`, are only read,
+/// a copy made with `save_copy` can be written.
 pub struct FilesystemStorage {
-  values: HashMap<String, Bytes>,
+  path: PathBuf,
+  legacy: bool,
+  values: BTreeMap<String, Bytes>,
+  // Keys whose files are outdated. All of them are recorded in the log
+  dirty: BTreeSet<String>,
+  wal: Option<File>,
+  wal_size: u64,
+  // Checkpoint with the state the storage had at some point, and the keys changed since then
+  last_checkpoint: Option<PathBuf>,
+  changed: BTreeSet<String>,
+}
+
+impl FilesystemStorage {
+  fn wal_path(&self) -> PathBuf {
+    self.path.join(WAL_FILENAME)
+  }
+
+  fn replay_wal(&mut self) -> Result<()> {
+    let wal_path = self.wal_path();
+    let contents = match std::fs::read(&wal_path) {
+      Ok(contents) => contents,
+      Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+      Err(err) => return Err(err.into()),
+    };
+    let (changes, valid_size) = decode_records(&contents);
+    for change in changes {
+      self.apply(change);
+    }
+
+    let wal = OpenOptions::new().append(true).open(&wal_path)?;
+    if valid_size < contents.len() {
+      // Only the last write could have been interrupted and it was never acknowledged
+      warn!(
+        "Dropping {} bytes of an incomplete record from {}",
+        contents.len() - valid_size,
+        wal_path.display()
+      );
+      wal.set_len(valid_size as u64)?;
+      wal.sync_all()?;
+    }
+    self.wal = Some(wal);
+    self.wal_size = valid_size as u64;
+    Ok(())
+  }
+
+  // Keys would be written in a different format than the existing ones
+  fn check_writable(&self) -> Result<()> {
+    if self.legacy {
+      return Err(Error::DbError(format!(
+        "{} was written by an older version, only its copy can be written",
+        self.path.display()
+      )));
+    }
+    Ok(())
+  }
+
+  fn append_to_wal(&mut self, changes: &[Change]) -> Result<()> {
+    if self.wal.is_none() {
+      if !self.path.exists() {
+        if let Some(parent) = self.path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+          std::fs::create_dir_all(parent)?;
+        }
+        create_dir(&self.path)?;
+      }
+      let wal = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(self.wal_path())?;
+      sync_dir(&self.path)?;
+      self.wal = Some(wal);
+    }
+    let record = encode_record(changes)?;
+    let wal = self.wal.as_mut().unwrap();
+    if let Err(err) = wal.write_all(&record).and_then(|()| wal.sync_data()) {
+      // Don't leave a partial record in front of the following ones
+      wal.set_len(self.wal_size)?;
+      return Err(err.into());
+    }
+    self.wal_size += record.len() as u64;
+    Ok(())
+  }
+
+  fn apply(&mut self, (key, value): Change) {
+    match value {
+      Some(value) => self.values.insert(key.clone(), value),
+      None => self.values.remove(&key),
+    };
+    self.changed.insert(key.clone());
+    self.dirty.insert(key);
+  }
+
+  fn changed_path(&self) -> PathBuf {
+    self.path.join(CHANGED_FILENAME)
+  }
+
+  fn save_changed(&self) -> Result<()> {
+    if let Some(checkpoint) = &self.last_checkpoint {
+      let changed = ChangedKeys {
+        checkpoint: checkpoint.clone(),
+        keys: self.changed.clone(),
+      };
+      let contents = serde_json::to_vec(&changed).map_err(std::io::Error::from)?;
+      std::fs::create_dir_all(&self.path)?;
+      write_atomic(self.changed_path(), contents)?;
+    }
+    Ok(())
+  }
+
+  // Keys which may differ from the ones in `checkpoint`
+  fn changed_since(&self, checkpoint: &Path) -> Result<BTreeSet<String>> {
+    if self.last_checkpoint.as_deref() == Some(checkpoint) {
+      return Ok(self.changed.clone());
+    }
+    let checkpoint_values = <Self as interface::KVStorage>::open(checkpoint)?.values;
+    let mut changed: BTreeSet<_> = self
+      .values
+      .iter()
+      .filter(|(key, value)| checkpoint_values.get(*key) != Some(*value))
+      .map(|(key, _)| key.clone())
+      .collect();
+    changed.extend(
+      checkpoint_values
+        .into_keys()
+        .filter(|key| !self.values.contains_key(key)),
+    );
+    Ok(changed)
+  }
+
+  fn write_changes(&mut self, changes: Vec<Change>) -> Result<()> {
+    if changes.is_empty() {
+      return Ok(());
+    }
+    changes.iter().try_for_each(|(key, _)| validate_key(key))?;
+    self.check_writable()?;
+    self.append_to_wal(&changes)?;
+    for change in changes {
+      self.apply(change);
+    }
+    if self.wal_size >= WAL_COMPACTION_SIZE {
+      // The changes are already durable, so it can be retried with the next write
+      if let Err(err) = self.compact() {
+        error!("Failed to compact {}: {}", self.wal_path().display(), err);
+      }
+    }
+    Ok(())
+  }
+
+  // Dumps the changed keys to their files and truncates the log
+  fn compact(&mut self) -> Result<()> {
+    self.check_writable()?;
+    let mut changed_dirs = BTreeSet::new();
+    for key in &self.dirty {
+      let filepath = self.path.join(key_path(key));
+      match self.values.get(key) {
+        Some(value) => write_file(&filepath, value)?,
+        None => remove_file(&self.path, &filepath)?,
+      }
+      changed_dirs.extend(
+        filepath
+          .ancestors()
+          .skip(1)
+          .take_while(|dir| *dir != self.path)
+          .map(Path::to_owned),
+      );
+    }
+    for dir in changed_dirs {
+      if dir.exists() {
+        sync_dir(dir)?;
+      }
+    }
+    sync_dir(&self.path)?;
+    // Keys of the log are lost after the truncation
+    self.save_changed()?;
+    if let Some(wal) = &self.wal {
+      wal.set_len(0)?;
+      wal.sync_all()?;
+    }
+    self.dirty.clear();
+    self.wal_size = 0;
+    Ok(())
+  }
+}
+
+fn read_optional(path: &Path) -> Result<Option<Vec<u8>>> {
+  match std::fs::read(path) {
+    Ok(contents) => Ok(Some(contents)),
+    Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+    Err(err) => Err(err.into()),
+  }
+}
+
+fn write_file(path: &Path, value: &[u8]) -> Result<()> {
+  if let Some(parent) = path.parent() {
+    std::fs::create_dir_all(parent)?;
+  }
+  let mut file = File::create(path)?;
+  file.write_all(value)?;
+  file.sync_all()?;
+  Ok(())
+}
+
+// Removes the file of a key and the directories of long keys which became empty
+fn remove_file(root: &Path, path: &Path) -> Result<()> {
+  match std::fs::remove_file(path) {
+    Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+    _ => {}
+  }
+  for dir in path.ancestors().skip(1).take_while(|dir| *dir != root) {
+    if std::fs::remove_dir(dir).is_err() {
+      break;
+    }
+  }
+  Ok(())
+}
+
+// Record layout: payload length (u32), payload CRC32 (u32), payload.
+// Payload is a sequence of changes: tag (u8), key, and value for puts,
+// where byte strings are prefixed with their length (u32).
+fn encode_record(changes: &[Change]) -> Result<Vec<u8>> {
+  fn push_bytes(buffer: &mut Vec<u8>, bytes: &[u8]) -> Result<()> {
+    let len = u32::try_from(bytes.len())
+      .map_err(|_| Error::DbError("Value is too large".to_owned()))?;
+    buffer.extend_from_slice(&len.to_le_bytes());
+    buffer.extend_from_slice(bytes);
+    Ok(())
+  }
+
+  let mut payload = Vec::new();
+  for (key, value) in changes {
+    match value {
+      Some(value) => {
+        payload.push(PUT);
+        push_bytes(&mut payload, key.as_bytes())?;
+        push_bytes(&mut payload, value)?;
+      }
+      None => {
+        payload.push(DELETE);
+        push_bytes(&mut payload, key.as_bytes())?;
+      }
+    }
+  }
+  let len = u32::try_from(payload.len())
+    .map_err(|_| Error::DbError("Write batch is too large".to_owned()))?;
+  let mut record = Vec::with_capacity(payload.len() + 8);
+  record.extend_from_slice(&len.to_le_bytes());
+  record.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+  record.extend_from_slice(&payload);
+  Ok(record)
+}
+
+// Returns changes of all the complete records and the size they occupy
+fn decode_records(contents: &[u8]) -> (Vec<Change>, usize) {
+  fn take<'a>(buffer: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if buffer.len() < len {
+      return None;
+    }
+    let (head, tail) = buffer.split_at(len);
+    *buffer = tail;
+    Some(head)
+  }
+  fn take_u32(buffer: &mut &[u8]) -> Option<u32> {
+    take(buffer, 4).map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+  }
+  fn take_bytes<'a>(buffer: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let len = take_u32(buffer)? as usize;
+    take(buffer, len)
+  }
+  fn decode_record(buffer: &mut &[u8]) -> Option<Vec<Change>> {
+    let len = take_u32(buffer)? as usize;
+    let crc = take_u32(buffer)?;
+    let payload = take(buffer, len)?;
+    if crc32fast::hash(payload) != crc {
+      return None;
+    }
+    decode_payload(payload)
+  }
+  fn decode_payload(mut payload: &[u8]) -> Option<Vec<Change>> {
+    let mut changes = Vec::new();
+    while let Some(tag) = take(&mut payload, 1) {
+      let key = String::from_utf8(take_bytes(&mut payload)?.to_vec()).ok()?;
+      let value = match tag[0] {
+        PUT => Some(take_bytes(&mut payload)?.to_vec()),
+        DELETE => None,
+        _ => return None,
+      };
+      changes.push((key, value));
+    }
+    Some(changes)
+  }
+
+  let mut changes = Vec::new();
+  let mut rest = contents;
+  let mut buffer = rest;
+  while let Some(record) = decode_record(&mut buffer) {
+    changes.extend(record);
+    rest = buffer;
+  }
+  (changes, contents.len() - rest.len())
 }
 
 impl interface::KVStorage for FilesystemStorage {
   fn open(path: impl AsRef<Path>) -> Result<Self> {
     let path = path.as_ref();
-    if !path.exists() {
-      return Ok(Self {
-        values: HashMap::new(),
-      });
+    let delta = Delta::read_chain(path)?;
+    let mut result = Self {
+      path: path.to_owned(),
+      legacy: delta.legacy,
+      values: delta.values,
+      dirty: BTreeSet::new(),
+      wal: None,
+      wal_size: 0,
+      last_checkpoint: None,
+      changed: BTreeSet::new(),
+    };
+    if let Some(contents) = read_optional(&result.changed_path())? {
+      let changed: ChangedKeys = serde_json::from_slice(&contents).map_err(std::io::Error::from)?;
+      result.last_checkpoint = Some(changed.checkpoint);
+      result.changed = changed.keys;
     }
-    let mut values: HashMap<String, Bytes> = HashMap::new();
-    for file in path
-      .read_dir()
-      .unwrap_or_else(|_| panic!("Couldn't open dir {}", path.display()))
-      .flatten()
-    {
-      let filepath = file.path();
-      let key = filepath.file_name().unwrap().to_str().unwrap();
-      let value: Bytes =
-        std::fs::read(&filepath).unwrap_or_else(|_| panic!("Couldn't read file {}", filepath.display()));
-      values.insert(key.to_owned(), value);
-    }
-    Ok(Self { values })
+    result.replay_wal()?;
+    Ok(result)
   }
 
   fn destroy(path: impl AsRef<Path>) -> Result<()> {
@@ -61,20 +583,151 @@ impl interface::KVStorage for FilesystemStorage {
     Ok(result)
   }
 
+  fn scan(&self, range: &KeyRange, limit: usize) -> Result<Vec<KeyValue>> {
+    Ok(
+      range
+        .iter(&self.values)
+        .take(limit)
+        .map(|(key, value)| KeyValue {
+          key: key.clone(),
+          value: value.clone(),
+        })
+        .collect(),
+    )
+  }
+
   fn write(&mut self, parts: Vec<KeyValue>) -> Result<()> {
-    for part in parts.into_iter() {
-      self.values.insert(part.key, part.value);
-    }
-    Ok(())
+    self.write_changes(
+      parts
+        .into_iter()
+        .map(|part| (part.key, Some(part.value)))
+        .collect(),
+    )
+  }
+
+  fn delete<Key: AsRef<str>>(&mut self, keys: &[Key]) -> Result<()> {
+    self.write_changes(keys.iter().map(|key| (key.as_ref().to_owned(), None)).collect())
   }
 
   fn save_copy(&self, path: impl AsRef<Path>) -> Result<()> {
     let path = path.as_ref();
-    std::fs::create_dir(path)?;
+    create_dir(path)?;
     for (key, value) in self.values.iter() {
-      let filepath = path.join(key);
+      let filepath = path.join(key_path(key));
+      if let Some(parent) = filepath.parent() {
+        std::fs::create_dir_all(parent)?;
+      }
       std::fs::write(filepath, value)?;
     }
     Ok(())
   }
+
+  fn key_count(&self) -> Result<Option<KeyCount>> {
+    Ok(Some(KeyCount {
+      count: self.values.len() as u64,
+      estimated: false,
+    }))
+  }
+
+  fn is_legacy(&self) -> bool {
+    self.legacy
+  }
+
+  fn save_checkpoint(&self, path: impl AsRef<Path>, parent: Option<&Path>) -> Result<()> {
+    let parent = match parent {
+      Some(parent) if chain_length(parent)? < MAX_DELTA_CHAIN => parent,
+      _ => return self.save_copy(path),
+    };
+    let path = path.as_ref();
+    let changed = self.changed_since(parent)?;
+    create_dir(path)?;
+    let mut deleted = BTreeSet::new();
+    for key in changed {
+      match self.values.get(&key) {
+        Some(value) => {
+          let filepath = path.join(key_path(&key));
+          if let Some(parent) = filepath.parent() {
+            std::fs::create_dir_all(parent)?;
+          }
+          std::fs::write(filepath, value)?;
+        }
+        None => {
+          deleted.insert(key);
+        }
+      }
+    }
+    let deleted = serde_json::to_vec(&deleted).map_err(std::io::Error::from)?;
+    std::fs::write(path.join(DELETED_FILENAME), deleted)?;
+    let parent_name = parent.file_name().unwrap().to_string_lossy();
+    std::fs::write(path.join(PARENT_FILENAME), parent_name.as_bytes())?;
+    Ok(())
+  }
+
+  fn mark_checkpoint(&mut self, path: &Path) -> Result<()> {
+    self.check_writable()?;
+    self.last_checkpoint = Some(path.to_owned());
+    self.changed.clear();
+    // Keys of the log would be counted as changed again after a restart
+    if self.wal.is_some() {
+      self.compact()
+    } else {
+      self.save_changed()
+    }
+  }
+
+  fn rebase_checkpoint(path: &Path, new_parent: Option<&Path>) -> Result<()> {
+    let own = Delta::read(path)?;
+    let new_parent_name =
+      new_parent.map(|parent| parent.file_name().unwrap().to_string_lossy().into_owned());
+    // Changes of the checkpoints between the old and the new parent, the latest ones win.
+    // Reaching the end of the chain makes the checkpoint complete
+    let mut inherited = Delta::default();
+    let mut current = own.parent.clone();
+    while let Some(name) = current.as_ref().filter(|name| Some(*name) != new_parent_name.as_ref()) {
+      let delta = Delta::read(&path.with_file_name(name))?;
+      let is_new = |key: &String| {
+        !own.values.contains_key(key)
+          && !own.deleted.contains(key)
+          && !inherited.values.contains_key(key)
+          && !inherited.deleted.contains(key)
+      };
+      let values: Vec<_> = delta.values.into_iter().filter(|(key, _)| is_new(key)).collect();
+      let deleted: Vec<_> = delta.deleted.into_iter().filter(is_new).collect();
+      inherited.values.extend(values);
+      inherited.deleted.extend(deleted);
+      current = delta.parent;
+    }
+    if current == own.parent {
+      return Ok(());
+    }
+
+    // Every step keeps the contents of the checkpoint the same
+    for (key, value) in &inherited.values {
+      let filepath = path.join(key_path(key));
+      if let Some(parent) = filepath.parent() {
+        std::fs::create_dir_all(parent)?;
+      }
+      write_atomic(filepath, value)?;
+    }
+    let parent_path = path.join(PARENT_FILENAME);
+    let deleted_path = path.join(DELETED_FILENAME);
+    match current {
+      Some(name) => {
+        let mut deleted = own.deleted;
+        deleted.extend(inherited.deleted);
+        let deleted = serde_json::to_vec(&deleted).map_err(std::io::Error::from)?;
+        write_atomic(&deleted_path, deleted)?;
+        write_atomic(&parent_path, name.as_bytes())?;
+      }
+      None => {
+        std::fs::remove_file(&parent_path)?;
+        sync_dir(path)?;
+        if deleted_path.exists() {
+          std::fs::remove_file(&deleted_path)?;
+        }
+      }
+    }
+    sync_dir(path)?;
+    Ok(())
+  }
 }
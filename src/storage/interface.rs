@@ -1,11 +1,60 @@
 use std::path::Path;
-use crate::types::{Bytes, KeyValue, Result};
+use crate::types::{Bytes, KeyRange, KeyValue, Result};
+
+// Number of stored keys, which backends that can't count them cheaply only estimate
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyCount {
+  pub count: u64,
+  pub estimated: bool,
+}
 
 pub trait KVStorage: Sized + Sync + Send {
   fn open(path: impl AsRef<Path>) -> Result<Self>;
+  // Opens a storage which is only read, e.g. a checkpoint. Writing to it may fail
+  fn open_read_only(path: impl AsRef<Path>) -> Result<Self> {
+    Self::open(path)
+  }
   fn destroy(path: impl AsRef<Path>) -> Result<()>;
+  // Only used by tests
+  #[allow(dead_code)]
   fn get_one<Key: AsRef<str>>(&self, key: Key) -> Result<Bytes>;
   fn get<Key: AsRef<str>>(&self, keys: &[Key]) -> Result<Vec<KeyValue>>;
+  // Returns at most `limit` entries of the range ordered by key
+  fn scan(&self, range: &KeyRange, limit: usize) -> Result<Vec<KeyValue>>;
   fn write(&mut self, parts: Vec<KeyValue>) -> Result<()>;
+  fn delete<Key: AsRef<str>>(&mut self, keys: &[Key]) -> Result<()>;
   fn save_copy(&self, path: impl AsRef<Path>) -> Result<()>;
+  // `None` if the backend can't even estimate it
+  fn key_count(&self) -> Result<Option<KeyCount>>;
+  // Written in the format of an older version, which can be read but not written.
+  // A copy made with `save_copy` is in the current format
+  fn is_legacy(&self) -> bool {
+    false
+  }
+
+  // Makes a copy of a closed storage at `dst`. Backends override it when they can do it
+  // without reading all the data
+  fn clone_dir(src: impl AsRef<Path>, dst: impl AsRef<Path>) -> Result<()> {
+    Self::open(src)?.save_copy(dst)
+  }
+
+  // Saves the current state at `path`. Backends supporting deltas store only the changes
+  // against `parent`, a checkpoint in the same directory which must be kept while `path` is
+  fn save_checkpoint(&self, path: impl AsRef<Path>, parent: Option<&Path>) -> Result<()> {
+    let _ = parent;
+    self.save_copy(path)
+  }
+
+  // Remembers that `path` has the current state, so that the next delta is cheap
+  fn mark_checkpoint(&mut self, path: &Path) -> Result<()> {
+    let _ = path;
+    Ok(())
+  }
+
+  // Makes the checkpoint at `path` depend on `new_parent`, an earlier checkpoint of its chain,
+  // or on nothing. The contents stay the same, so the checkpoints in between can be removed
+  fn rebase_checkpoint(path: &Path, new_parent: Option<&Path>) -> Result<()> {
+    let _ = (path, new_parent);
+    Ok(())
+  }
 }
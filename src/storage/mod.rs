@@ -1,3 +1,4 @@
+pub mod any;
 pub mod filesystem;
 pub mod interface;
 pub mod rocksdb;
@@ -1,7 +1,8 @@
-use super::interface::KVStorage;
-use crate::types::{Bytes, Error, KeyValue, Result};
+use super::interface::{KVStorage, KeyCount};
+use crate::types::{Bytes, Error, KeyRange, KeyValue, Result};
 use rocksdb::{
-  checkpoint::Checkpoint, BlockBasedOptions, Cache, Error as RocksdbError, Options, WriteBatch, DB,
+  checkpoint::Checkpoint, BlockBasedOptions, Cache, Direction, Error as RocksdbError, IteratorMode,
+  Options, ReadOptions, WriteBatch, DB,
 };
 use std::io::Write;
 use std::path::Path;
@@ -9,7 +10,8 @@ use std::sync::mpsc::{sync_channel, SyncSender, TryRecvError};
 
 pub struct RocksdbStorage {
   db: DB,
-  _sender: SyncSender<()>,
+  // Stops the statistics thread when dropped, read-only storages don't have it
+  _sender: Option<SyncSender<()>>,
 }
 
 impl From<RocksdbError> for Error {
@@ -49,10 +51,15 @@ impl KVStorage for RocksdbStorage {
 
     Ok(Self {
       db,
-      _sender: sender,
+      _sender: Some(sender),
     })
   }
 
+  fn open_read_only(path: impl AsRef<Path>) -> Result<Self> {
+    let db = DB::open_for_read_only(&Options::default(), &path, false)?;
+    Ok(Self { db, _sender: None })
+  }
+
   fn destroy(path: impl AsRef<Path>) -> Result<()> {
     DB::destroy(&Options::default(), &path)?;
     std::fs::remove_dir_all(path)?;
@@ -84,6 +91,30 @@ impl KVStorage for RocksdbStorage {
     Ok(result)
   }
 
+  fn scan(&self, range: &KeyRange, limit: usize) -> Result<Vec<KeyValue>> {
+    let mut read_options = ReadOptions::default();
+    if let Some(end) = &range.end {
+      read_options.set_iterate_upper_bound(end.as_bytes());
+    }
+    let mode = IteratorMode::From(range.first_key().as_bytes(), Direction::Forward);
+    let mut result = Vec::new();
+    for (key, value) in self.db.iterator_opt(mode, read_options) {
+      if result.len() >= limit {
+        break;
+      }
+      let key = String::from_utf8(key.into_vec())
+        .map_err(|err| Error::DbError(format!("Non UTF-8 key in db: {}", err)))?;
+      if range.is_past(&key) {
+        break;
+      }
+      result.push(KeyValue {
+        key,
+        value: value.into_vec(),
+      });
+    }
+    Ok(result)
+  }
+
   fn write(&mut self, parts: Vec<KeyValue>) -> Result<()> {
     let mut batch = WriteBatch::default();
     for part in parts.into_iter() {
@@ -93,6 +124,15 @@ impl KVStorage for RocksdbStorage {
     Ok(())
   }
 
+  fn delete<Key: AsRef<str>>(&mut self, keys: &[Key]) -> Result<()> {
+    let mut batch = WriteBatch::default();
+    for key in keys {
+      batch.delete(key.as_ref());
+    }
+    self.db.write(batch)?;
+    Ok(())
+  }
+
   fn save_copy(&self, path: impl AsRef<Path>) -> Result<()> {
     // TODO: consider reusing the same checkpoint manager.
     // It is problematic because storing it in the struct would cause
@@ -101,4 +141,33 @@ impl KVStorage for RocksdbStorage {
     checkpoint_manager.create_checkpoint(path)?;
     Ok(())
   }
+
+  // Counting would read the whole database
+  fn key_count(&self) -> Result<Option<KeyCount>> {
+    let count = self.db.property_int_value("rocksdb.estimate-num-keys")?;
+    Ok(count.map(|count| KeyCount {
+      count,
+      estimated: true,
+    }))
+  }
+
+  // Table files are never modified once written, so they are shared with the copy.
+  // Only the small metadata and log files are copied
+  fn clone_dir(src: impl AsRef<Path>, dst: impl AsRef<Path>) -> Result<()> {
+    let dst = dst.as_ref();
+    std::fs::create_dir(dst)?;
+    for entry in std::fs::read_dir(src)? {
+      let entry = entry?;
+      let file_name = entry.file_name();
+      let dst_path = dst.join(&file_name);
+      let immutable = Path::new(&file_name)
+        .extension()
+        .is_some_and(|extension| extension == "sst" || extension == "blob");
+      if immutable && std::fs::hard_link(entry.path(), &dst_path).is_ok() {
+        continue;
+      }
+      std::fs::copy(entry.path(), &dst_path)?;
+    }
+    Ok(())
+  }
 }
@@ -1,6 +1,8 @@
 use super::filesystem::FilesystemStorage;
 use super::interface::KVStorage;
-use crate::types::KeyValue;
+use crate::types::{KeyRange, KeyValue};
+use std::io::Write;
+use std::path::Path;
 
 fn part(key: impl AsRef<str>, value: impl AsRef<[u8]>) -> KeyValue {
   KeyValue {
@@ -11,20 +13,182 @@ fn part(key: impl AsRef<str>, value: impl AsRef<[u8]>) -> KeyValue {
 
 #[test]
 fn test_filesystem_storage() {
-  const PATH0: &str = "test_db_0";
-  const PATH1: &str = "test_db_1";
+  let dir = tempfile::tempdir().unwrap();
+  let (path0, path1) = (dir.path().join("0"), dir.path().join("1"));
 
-  FilesystemStorage::destroy(PATH0).unwrap();
-  FilesystemStorage::destroy(PATH1).unwrap();
-
-  let mut storage = FilesystemStorage::open(PATH0).unwrap();
+  let mut storage = FilesystemStorage::open(&path0).unwrap();
   assert!(storage.get_one("a").is_err());
   storage.write(vec![part("a", "123\n456"), part("b", "")]).unwrap();
   assert_eq!(storage.get(&["a", "b", "c"]).unwrap(), vec![part("a", "123\n456"), part("b", "")]);
-  storage.save_copy(PATH1).unwrap();
+  storage.save_copy(&path1).unwrap();
 
   drop(storage);
 
-  let storage = FilesystemStorage::open(PATH1).unwrap();
+  let mut storage = FilesystemStorage::open(&path1).unwrap();
   assert_eq!(storage.get_one("a").unwrap(), b"123\n456");
+  storage.delete(&["a", "c"]).unwrap();
+  assert_eq!(storage.get(&["a", "b", "c"]).unwrap(), vec![part("b", "")]);
+  FilesystemStorage::destroy(&path0).unwrap();
+  storage.save_copy(&path0).unwrap();
+
+  let storage = FilesystemStorage::open(&path0).unwrap();
+  assert_eq!(storage.get(&["a", "b", "c"]).unwrap(), vec![part("b", "")]);
+}
+
+#[test]
+fn test_filesystem_scan() {
+  let dir = tempfile::tempdir().unwrap();
+  let path = dir.path().join("db");
+
+  let mut storage = FilesystemStorage::open(&path).unwrap();
+  storage
+    .write(vec![part("x1", "1"), part("y1", "2"), part("y2", "3"), part("y3", "4")])
+    .unwrap();
+  let range = KeyRange {
+    prefix: "y".to_owned(),
+    start: "y2".to_owned(),
+    end: None,
+  };
+  assert_eq!(
+    storage.scan(&range, 10).unwrap(),
+    vec![part("y2", "3"), part("y3", "4")]
+  );
+  assert_eq!(storage.scan(&range, 1).unwrap(), vec![part("y2", "3")]);
+}
+
+#[test]
+fn test_filesystem_wal() {
+  let dir = tempfile::tempdir().unwrap();
+  let path = dir.path().join("db");
+
+  let mut storage = FilesystemStorage::open(&path).unwrap();
+  storage.write(vec![part("a", "1"), part("b", "2")]).unwrap();
+  storage.delete(&["a"]).unwrap();
+  assert!(storage.write(vec![part("", "")]).is_err());
+  drop(storage);
+
+  // A write interrupted by a crash
+  std::fs::OpenOptions::new()
+    .append(true)
+    .open(path.join(".wal"))
+    .unwrap()
+    .write_all(&[42, 0, 0, 0, 1])
+    .unwrap();
+
+  let mut storage = FilesystemStorage::open(&path).unwrap();
+  assert_eq!(storage.get(&["a", "b"]).unwrap(), vec![part("b", "2")]);
+  storage.write(vec![part("c", "3")]).unwrap();
+  drop(storage);
+
+  let storage = FilesystemStorage::open(&path).unwrap();
+  assert_eq!(
+    storage.get(&["a", "b", "c"]).unwrap(),
+    vec![part("b", "2"), part("c", "3")]
+  );
+}
+
+#[test]
+fn test_filesystem_keys() {
+  let dir = tempfile::tempdir().unwrap();
+  let (path0, path1) = (dir.path().join("0"), dir.path().join("1"));
+
+  let long_key = format!("{}/ключ/{}", "a".repeat(199), ".".repeat(300));
+  let keys = [".wal", "..", "../escape", "a/b", "%41", "a+", "-_.", long_key.as_str()];
+  let parts: Vec<_> = keys.iter().map(|key| part(key, key)).collect();
+  let mut storage = FilesystemStorage::open(&path0).unwrap();
+  storage.write(parts).unwrap();
+  storage.save_copy(&path1).unwrap();
+
+  let storage = FilesystemStorage::open(&path1).unwrap();
+  let mut expected: Vec<_> = keys.iter().map(|key| part(key, key)).collect();
+  expected.sort_by(|a, b| a.key.cmp(&b.key));
+  assert_eq!(storage.scan(&KeyRange::default(), 100).unwrap(), expected);
+  assert!(!dir.path().join("escape").exists());
+  assert!(!storage.is_legacy());
+}
+
+#[test]
+fn test_filesystem_legacy() {
+  let dir = tempfile::tempdir().unwrap();
+  let (path0, path1) = (dir.path().join("0"), dir.path().join("1"));
+
+  // Written by older versions which stored keys as is, without a format file
+  let keys = ["%41", ".hidden", "a/b", "legacy:key", ".format"];
+  for key in keys {
+    let path = path0.join(key);
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    std::fs::write(path, key).unwrap();
+  }
+  let mut storage = FilesystemStorage::open(&path0).unwrap();
+  assert!(storage.is_legacy());
+  assert!(storage.write(vec![part("b", "1")]).is_err());
+  let mut expected: Vec<_> = keys.iter().map(|key| part(key, key)).collect();
+  expected.sort_by(|a, b| a.key.cmp(&b.key));
+  assert_eq!(storage.scan(&KeyRange::default(), 100).unwrap(), expected);
+  // Nothing is renamed or removed
+  assert!(path0.join("%41").exists());
+
+  storage.save_copy(&path1).unwrap();
+  let mut storage = FilesystemStorage::open(&path1).unwrap();
+  assert!(!storage.is_legacy());
+  assert_eq!(storage.scan(&KeyRange::default(), 100).unwrap(), expected);
+  storage.write(vec![part("b", "1")]).unwrap();
+}
+
+#[test]
+fn test_filesystem_checkpoints() {
+  let dir = tempfile::tempdir().unwrap();
+  let head = dir.path().join("HEAD");
+  let checkpoint = |id: &str| dir.path().join(id);
+  let scan = |path: &Path| {
+    FilesystemStorage::open(path)
+      .unwrap()
+      .scan(&KeyRange::default(), 100)
+      .unwrap()
+  };
+
+  let mut storage = FilesystemStorage::open(&head).unwrap();
+  storage.write(vec![part("a", "1"), part("b", "1"), part("c", "1")]).unwrap();
+  storage.save_checkpoint(checkpoint("0"), None).unwrap();
+  storage.mark_checkpoint(&checkpoint("0")).unwrap();
+  storage.write(vec![part("a", "2")]).unwrap();
+  storage.delete(&["b"]).unwrap();
+  storage.save_checkpoint(checkpoint("1"), Some(&checkpoint("0"))).unwrap();
+  storage.mark_checkpoint(&checkpoint("1")).unwrap();
+  storage.write(vec![part("b", "3")]).unwrap();
+  storage.delete(&["c"]).unwrap();
+  drop(storage);
+
+  // The changes since the last checkpoint survive a restart
+  let storage = FilesystemStorage::open(&head).unwrap();
+  storage.save_checkpoint(checkpoint("2"), Some(&checkpoint("1"))).unwrap();
+  assert!(!checkpoint("1").join("c").exists());
+  assert!(!checkpoint("2").join("a").exists());
+
+  let expected1 = vec![part("a", "2"), part("c", "1")];
+  let expected2 = vec![part("a", "2"), part("b", "3")];
+  assert_eq!(scan(&checkpoint("1")), expected1);
+  assert_eq!(scan(&checkpoint("2")), expected2);
+
+  FilesystemStorage::rebase_checkpoint(&checkpoint("2"), Some(&checkpoint("0"))).unwrap();
+  FilesystemStorage::destroy(checkpoint("1")).unwrap();
+  assert_eq!(scan(&checkpoint("2")), expected2);
+  FilesystemStorage::rebase_checkpoint(&checkpoint("2"), None).unwrap();
+  FilesystemStorage::destroy(checkpoint("0")).unwrap();
+  assert_eq!(scan(&checkpoint("2")), expected2);
+
+  // Long chains are cut by complete checkpoints
+  let mut storage = FilesystemStorage::open(&head).unwrap();
+  storage.mark_checkpoint(&checkpoint("2")).unwrap();
+  for i in 3..40 {
+    storage.write(vec![part("d", i.to_string())]).unwrap();
+    let parent = checkpoint(&(i - 1).to_string());
+    storage.save_checkpoint(checkpoint(&i.to_string()), Some(&parent)).unwrap();
+    storage.mark_checkpoint(&checkpoint(&i.to_string())).unwrap();
+  }
+  let complete: Vec<_> = (3..40).filter(|i| !checkpoint(&i.to_string()).join(".parent").exists()).collect();
+  assert!(!complete.is_empty() && complete.windows(2).all(|pair| pair[1] - pair[0] > 1));
+  for i in ["10", "39"] {
+    assert_eq!(scan(&checkpoint(i)), vec![part("a", "2"), part("b", "3"), part("d", i)]);
+  }
 }
@@ -1,18 +1,125 @@
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::ops::Bound;
+use std::path::Path;
 use thiserror::Error;
 
 pub type Bytes = Vec<u8>;
 
+/// Longer keys could exceed the path length limit of the filesystem storage
+pub const MAX_KEY_LEN: usize = 1024;
+
+pub fn validate_key(key: &str) -> Result<()> {
+  if key.is_empty() {
+    return Err(Error::InvalidArgument("Key can't be empty".to_owned()));
+  }
+  if key.len() > MAX_KEY_LEN {
+    return Err(Error::InvalidArgument(format!(
+      "Key is longer than {} bytes",
+      MAX_KEY_LEN
+    )));
+  }
+  Ok(())
+}
+
+/// Application id which is safe to use as a file name:
+/// up to 128 ASCII letters, digits, `_`, `-` and `.`, not starting with a dot.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct AppId(String);
+
+impl AppId {
+  pub const MAX_LEN: usize = 128;
+
+  pub fn new(id: impl Into<String>) -> Result<Self> {
+    let id = id.into();
+    let valid_chars = id
+      .chars()
+      .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.');
+    // Dot-prefixed names are reserved for temporary directories
+    if id.is_empty() || id.len() > Self::MAX_LEN || !valid_chars || id.starts_with('.') {
+      return Err(Error::InvalidArgument(format!("Invalid app id {:?}", id)));
+    }
+    Ok(Self(id))
+  }
+
+  pub fn as_str(&self) -> &str {
+    &self.0
+  }
+}
+
+impl AsRef<str> for AppId {
+  fn as_ref(&self) -> &str {
+    &self.0
+  }
+}
+
+impl AsRef<Path> for AppId {
+  fn as_ref(&self) -> &Path {
+    self.0.as_ref()
+  }
+}
+
+impl Display for AppId {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.write_str(&self.0)
+  }
+}
+
 #[derive(Debug, Default, PartialEq, Eq)]
 pub struct KeyValue {
   pub key: String,
   pub value: Bytes,
 }
 
+/// Keys starting with `prefix` within `[start, end)`. Empty `start` means no lower bound.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct KeyRange {
+  pub prefix: String,
+  pub start: String,
+  pub end: Option<String>,
+}
+
+impl KeyRange {
+  /// Smallest key which may belong to the range
+  pub fn first_key(&self) -> &str {
+    std::cmp::max(&self.prefix, &self.start)
+  }
+
+  /// Whether `key` and all the keys after it are out of the range.
+  /// Only meaningful for keys not less than `first_key()`.
+  pub fn is_past(&self, key: &str) -> bool {
+    !key.starts_with(&self.prefix) || self.end.as_ref().is_some_and(|end| key >= end.as_str())
+  }
+
+  /// Iterates over the entries of a sorted map which belong to the range
+  pub fn iter<'a, V>(&'a self, map: &'a BTreeMap<String, V>) -> impl Iterator<Item = (&'a String, &'a V)> {
+    map
+      .range::<str, _>((Bound::Included(self.first_key()), Bound::Unbounded))
+      .take_while(|(key, _)| !self.is_past(key))
+  }
+}
+
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug, Error)]
 pub enum Error {
   #[error("{0}")]
   NotFound(String),
 
+  #[error("{0}")]
+  AlreadyExists(String),
+
+  #[error("{0}")]
+  Unimplemented(String),
+
+  #[error("{0}")]
+  FailedPrecondition(String),
+
+  #[error("{0}")]
+  InvalidArgument(String),
+
+  #[error("{0}")]
+  ResourceExhausted(String),
+
   #[error("DB error: {0}")]
   DbError(String),
 
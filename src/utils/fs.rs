@@ -0,0 +1,50 @@
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use walkdir::WalkDir;
+
+// Makes creation, removal and renaming of the directory entries durable
+pub fn sync_dir(path: impl AsRef<Path>) -> std::io::Result<()> {
+  File::open(path)?.sync_all()
+}
+
+// Flushes all the files and directories under `path` to disk
+pub fn sync_tree(path: impl AsRef<Path>) -> std::io::Result<()> {
+  for entry in WalkDir::new(path) {
+    let entry = entry?;
+    // Directories are synced as well, so that their entries are durable
+    File::open(entry.path())?.sync_all()?;
+  }
+  Ok(())
+}
+
+// Total size of the files under `path` in bytes
+pub fn dir_size(path: impl AsRef<Path>) -> std::io::Result<u64> {
+  let mut size = 0;
+  for entry in WalkDir::new(path) {
+    let entry = entry?;
+    if entry.file_type().is_file() {
+      size += entry.metadata()?.len();
+    }
+  }
+  Ok(size)
+}
+
+// Replaces the file so that readers see either the old or the new contents, even after a crash
+pub fn write_atomic(path: impl AsRef<Path>, contents: impl AsRef<[u8]>) -> std::io::Result<()> {
+  let path = path.as_ref();
+  // Hidden, so that it isn't mistaken for a regular file of the directory
+  let mut tmp_name = std::ffi::OsString::from(".");
+  tmp_name.push(path.file_name().unwrap_or_default());
+  tmp_name.push(".tmp");
+  let tmp_path = path.with_file_name(tmp_name);
+
+  let mut file = File::create(&tmp_path)?;
+  file.write_all(contents.as_ref())?;
+  file.sync_all()?;
+  std::fs::rename(&tmp_path, path)?;
+  match path.parent() {
+    Some(parent) if !parent.as_os_str().is_empty() => sync_dir(parent),
+    _ => sync_dir("."),
+  }
+}
@@ -1 +1,13 @@
 pub mod exponential_sequence;
+pub mod fs;
+pub mod spool;
+
+use rand::{distributions::Alphanumeric, Rng};
+
+pub fn random_string(len: usize) -> String {
+  rand::thread_rng()
+    .sample_iter(&Alphanumeric)
+    .take(len)
+    .map(char::from)
+    .collect()
+}
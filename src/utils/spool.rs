@@ -0,0 +1,183 @@
+use super::random_string;
+use crate::types::KeyValue;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+// Bounds of a chunk of spooled pairs written at once
+pub const CHUNK_KEYS: usize = 10_000;
+pub const CHUNK_BYTES: usize = 64 << 20;
+
+/// Temporary file of key-value pairs received before they are applied, removed when dropped
+/// unless it's persisted.
+pub struct Spool {
+  path: PathBuf,
+  writer: BufWriter<File>,
+  len: u64,
+}
+
+impl Spool {
+  pub fn create(dir: impl AsRef<Path>) -> std::io::Result<Self> {
+    // Hidden, so that it isn't mistaken for an app if the directory is the db root
+    let path = dir.as_ref().join(format!(".spool-{}", random_string(8)));
+    let writer = BufWriter::new(File::create(&path)?);
+    Ok(Self {
+      path,
+      writer,
+      len: 0,
+    })
+  }
+
+  pub fn push(&mut self, key: &str, value: &[u8]) -> std::io::Result<()> {
+    // Lengths make the encoding unambiguous
+    self.writer.write_all(&(key.len() as u64).to_le_bytes())?;
+    self.writer.write_all(key.as_bytes())?;
+    self.writer.write_all(&(value.len() as u64).to_le_bytes())?;
+    self.writer.write_all(value)?;
+    self.len += 1;
+    Ok(())
+  }
+
+  // Number of pushed pairs
+  pub fn count(&self) -> u64 {
+    self.len
+  }
+
+  // Directory of the file, where spools derived from it are created
+  pub fn dir(&self) -> &Path {
+    self.path.parent().unwrap_or_else(|| Path::new("."))
+  }
+
+  // Reads the pairs in the order they were pushed, in chunks of at most `max_keys` pairs
+  // and at most `max_bytes` of values unless a single value is larger
+  pub fn chunks(&mut self, max_keys: usize, max_bytes: usize) -> std::io::Result<Chunks> {
+    self.writer.flush()?;
+    Chunks::open(&self.path, max_keys, max_bytes)
+  }
+
+  // Durably moves the file to `path`, where it's kept until it's removed explicitly
+  pub fn persist(mut self, path: impl AsRef<Path>) -> std::io::Result<()> {
+    self.writer.flush()?;
+    self.writer.get_ref().sync_all()?;
+    // The spool directory may be on another volume, then the original is removed on drop
+    if std::fs::rename(&self.path, &path).is_err() {
+      std::fs::copy(&self.path, &path)?;
+      File::open(&path)?.sync_all()?;
+    }
+    match path.as_ref().parent() {
+      Some(parent) if !parent.as_os_str().is_empty() => super::fs::sync_dir(parent),
+      _ => super::fs::sync_dir("."),
+    }
+  }
+}
+
+impl Drop for Spool {
+  fn drop(&mut self) {
+    let _ = std::fs::remove_file(&self.path);
+  }
+}
+
+pub struct Chunks {
+  reader: BufReader<File>,
+  is_done: bool,
+  max_keys: usize,
+  max_bytes: usize,
+}
+
+impl Chunks {
+  // Reads the pairs of a spool file, e.g. a persisted one
+  pub fn open(path: impl AsRef<Path>, max_keys: usize, max_bytes: usize) -> std::io::Result<Self> {
+    Ok(Self {
+      reader: BufReader::new(File::open(path)?),
+      is_done: false,
+      max_keys,
+      max_bytes,
+    })
+  }
+
+  fn read_bytes(&mut self) -> std::io::Result<Vec<u8>> {
+    let mut len = [0; 8];
+    self.reader.read_exact(&mut len)?;
+    let mut bytes = vec![0; u64::from_le_bytes(len) as usize];
+    self.reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+  }
+
+  // `None` at the end of the file
+  fn read_part(&mut self) -> std::io::Result<Option<KeyValue>> {
+    if self.reader.fill_buf()?.is_empty() {
+      return Ok(None);
+    }
+    let key = String::from_utf8(self.read_bytes()?)
+      .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+    let value = self.read_bytes()?;
+    Ok(Some(KeyValue { key, value }))
+  }
+}
+
+impl Iterator for Chunks {
+  type Item = std::io::Result<Vec<KeyValue>>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    let mut chunk = Vec::new();
+    let mut bytes = 0;
+    let (max_keys, max_bytes) = (self.max_keys, self.max_bytes);
+    let is_full = |chunk: &Vec<KeyValue>, bytes| {
+      chunk.len() >= max_keys || (!chunk.is_empty() && bytes >= max_bytes)
+    };
+    while !self.is_done && !is_full(&chunk, bytes) {
+      match self.read_part() {
+        Ok(Some(part)) => {
+          bytes += part.value.len();
+          chunk.push(part);
+        }
+        Ok(None) => self.is_done = true,
+        Err(err) => {
+          // The rest of the file can't be read after an error
+          self.is_done = true;
+          return Some(Err(err));
+        }
+      }
+    }
+    if chunk.is_empty() {
+      None
+    } else {
+      Some(Ok(chunk))
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_spool() {
+    let mut spool = Spool::create(std::env::temp_dir()).unwrap();
+    let path = spool.path.clone();
+    for i in 0..5 {
+      spool.push(&format!("key{}", i), &vec![b'x'; i]).unwrap();
+    }
+    assert_eq!(spool.count(), 5);
+
+    let chunks: Vec<_> = spool.chunks(2, 100).unwrap().map(Result::unwrap).collect();
+    let sizes: Vec<_> = chunks.iter().map(Vec::len).collect();
+    assert_eq!(sizes, [2, 2, 1]);
+    assert_eq!(chunks[2][0].key, "key4");
+    assert_eq!(chunks[2][0].value, b"xxxx");
+    // Chunks are closed once the values reach the limit
+    let sizes: Vec<_> = spool.chunks(10, 3).unwrap().map(|chunk| chunk.unwrap().len()).collect();
+    assert_eq!(sizes, [3, 1, 1]);
+
+    drop(spool);
+    assert!(!path.exists());
+
+    let mut spool = Spool::create(std::env::temp_dir()).unwrap();
+    spool.push("key", b"value").unwrap();
+    let persisted_path = std::env::temp_dir().join(format!("persisted-{}", random_string(8)));
+    spool.persist(&persisted_path).unwrap();
+    let chunks: Vec<_> = Chunks::open(&persisted_path, 10, 100).unwrap().map(Result::unwrap).collect();
+    assert_eq!(chunks, [vec![KeyValue { key: "key".to_owned(), value: b"value".to_vec() }]]);
+    std::fs::remove_file(persisted_path).unwrap();
+  }
+}